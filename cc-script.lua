@@ -1,4 +1,12 @@
 local RADIUS = 8
+local PROTOCOL_VERSION = 1
+-- Where to connect, set with `set cc-websockets.url <url>`. Either the viewer
+-- itself, e.g. the wss://....ngrok-free.app address create_tunnel.sh prints,
+-- or the relay server's turtle endpoint, e.g. wss://relay.example.com/ws
+local URL = settings.get("cc-websockets.url")
+-- Set with `set cc-websockets.token <token>` when the server checks tokens
+local TOKEN = settings.get("cc-websockets.token", "")
+if not URL then error("Set where to connect with `set cc-websockets.url <url>`", 0) end
 
 function strIndex(tbl, val)
  for k,v in ipairs(tbl) do
@@ -6,19 +14,10 @@ function strIndex(tbl, val)
  end
 end
 
-function linearize(x, y, z)
- local r = RADIUS
- local w = 2 * r + 1
- return (z + r)*(w * w) + (y + r)*w + (x + r)
-end
-
+-- Only blocks that are there are sent, as {x, y, z, palette index}; the rest of the cube is air
 function serialize(data)
- local r = RADIUS
- local w = 2 * r + 1
- 
  local k = {}
  local v = {}
- for i=1, w*w*w do v[i] = 0 end
  
  for i=1,#data do
   local b = data[i]
@@ -27,38 +26,259 @@ function serialize(data)
    k[#k+1] = b["name"] 
   end
   
-  v[linearize(b["x"], b["y"], b["z"], r, w )+1] = strIndex(k, b["name"])
+  v[#v+1] = {b["x"], b["y"], b["z"], strIndex(k, b["name"])}
  end
  
+ if #k == 0 then k = textutils.empty_json_array end
+ if #v == 0 then v = textutils.empty_json_array end
+ 
  return k, v 
 end
 
-local ws = http.websocketAsync("wss://332d-178-232-110-172.ngrok-free.app")  
+local ws = http.websocketAsync(URL .. "?turtle_id=" .. os.getComputerID() .. "&token=" .. textutils.urlEncode(TOKEN))
 if not ws then print("Could not create websocket") end
 local _,_,ws = os.pullEvent("websocket_success")
 
+-- Pose tracking, headings follow Minecraft's compass where north is -Z
+local HEADINGS = {"north", "east", "south", "west"}
+local STEPS = {north={0, -1}, east={1, 0}, south={0, 1}, west={-1, 0}}
+local pose = {x=0, y=0, z=0, heading=1, source="dead_reckoning"}
+local pose_changed = true
+local fix = nil
+local moves_made, last_direction = 0, nil
+
+function step(dx, dy, dz, direction)
+ pose.x, pose.y, pose.z = pose.x + dx, pose.y + dy, pose.z + dz
+ pose_changed = true
+ moves_made = moves_made + 1
+ last_direction = direction
+end
+
+function turn(heading)
+ pose.heading = heading
+ pose_changed = true
+ moves_made = moves_made + 1
+ last_direction = nil
+end
+
+local moves = {
+ forward = function()
+  local s = STEPS[HEADINGS[pose.heading]]
+  step(s[1], 0, s[2], 1)
+ end,
+ back = function()
+  local s = STEPS[HEADINGS[pose.heading]]
+  step(-s[1], 0, -s[2], -1)
+ end,
+ up = function() step(0, 1, 0, nil) end,
+ down = function() step(0, -1, 0, nil) end,
+ turnLeft = function() turn((pose.heading + 2) % 4 + 1) end,
+ turnRight = function() turn(pose.heading % 4 + 1) end,
+}
+
+-- Dead reckon from every successful move, whoever called it
+for name, update in pairs(moves) do
+ local original = turtle[name]
+ turtle[name] = function(...)
+  local results = table.pack(original(...))
+  if results[1] then update() end
+  return table.unpack(results, 1, results.n)
+ end
+end
+
+function locate()
+ local x, y, z = gps.locate(0.5)
+ if not x then return end
+ x, y, z = math.floor(x + 0.5), math.floor(y + 0.5), math.floor(z + 0.5)
+ 
+ -- A single horizontal step between two fixes tells which way the turtle faces
+ if fix and moves_made == 1 and last_direction then
+  for i, name in ipairs(HEADINGS) do
+   local s = STEPS[name]
+   if x - fix.x == s[1] * last_direction and z - fix.z == s[2] * last_direction and y == fix.y then
+    pose.heading = i
+   end
+  end
+ end
+ 
+ fix = {x=x, y=y, z=z}
+ pose.x, pose.y, pose.z, pose.source = x, y, z, "gps"
+end
+
+function send_pose()
+ send({type="pose", x=pose.x, y=pose.y, z=pose.z, heading=HEADINGS[pose.heading], source=pose.source})
+ pose_changed = false
+ moves_made, last_direction = 0, nil
+end
+
+function send_inventory()
+ local fuel, limit = turtle.getFuelLevel(), turtle.getFuelLimit()
+ -- Both are "unlimited" when the server has fuel turned off
+ if type(fuel) ~= "number" then fuel, limit = nil, nil end
+ 
+ local slots = {}
+ for slot=1,16 do
+  local item = turtle.getItemDetail(slot)
+  slots[slot] = item and {name=item.name, count=item.count} or textutils.json_null
+ end
+ 
+ send({type="inventory", fuel=fuel, fuel_limit=limit, selected=turtle.getSelectedSlot(), slots=slots})
+end
+
+function encodable(value)
+ if value == nil then return textutils.json_null end
+ if pcall(textutils.serializeJSON, value) then return value end
+ return tostring(value)
+end
+
+function run(code)
+ local fn, err = loadstring(code)
+ if not fn then return false, err end
+ 
+ local results = table.pack(pcall(fn))
+ if not results[1] then return false, results[2] end
+ 
+ local values = {}
+ for i=2,results.n do values[i-1] = encodable(results[i]) end
+ if #values == 0 then values = textutils.empty_json_array end
+ 
+ return true, values
+end
+
+function send(msg)
+ msg["version"] = PROTOCOL_VERSION
+ ws.send(textutils.serializeJSON(msg))
+end
+
+send({type="status", id=os.getComputerID(), label=os.getComputerLabel()})
+locate()
+send_pose()
+send_inventory()
+
 local geo = peripheral.wrap("left")
 
-os.startTimer(0.5)
-
-while true do
- local event, _, msg, _ = os.pullEvent()
- if event == "websocket_message" then
-  if msg then 
-   success, response = pcall(loadstring(msg))
-   if success then
-    ws.send("0")
-   else
-    ws.send("1" .. response)
+-- Commands run one at a time in the order they arrived, the listener keeps scanning meanwhile
+local queue = {}
+local running = nil
+
+function send_queue()
+ local ids = {}
+ for _, command in ipairs(queue) do
+  if command.id then ids[#ids+1] = command.id end
+ end
+ if #ids == 0 then ids = textutils.empty_json_array end
+ send({type="queue", running=running and running.id, queued=ids})
+end
+
+function finish(command, success, response)
+ if pose_changed then
+  locate()
+  send_pose()
+ end
+ send_inventory()
+ if success then
+  send({type="command_result", id=command.id, values=response})
+ else
+  send({type="command_error", id=command.id, error=tostring(response)})
+ end
+end
+
+function worker()
+ while true do
+  if #queue == 0 then
+   os.pullEvent("command_queued")
+  else
+   running = table.remove(queue, 1)
+   send_queue()
+   local success, response = run(running.code)
+   local command = running
+   running = nil
+   finish(command, success, response)
+   send_queue()
+  end
+ end
+end
+
+function executor()
+ while true do
+  parallel.waitForAny(worker, function()
+   local id
+   repeat _, id = os.pullEvent("cancel_running") until running and running.id == id
+  end)
+  
+  -- The worker was stopped halfway through, a move may have happened without being counted
+  local command = running
+  running = nil
+  pose_changed = true
+  finish(command, false, "cancelled")
+  send_queue()
+ end
+end
+
+function receive(msg)
+ local request = textutils.unserializeJSON(msg)
+ local kind = type(request) == "table" and request["type"]
+ 
+ if kind == "cancel" then
+  local id = request["id"]
+  -- Raw Lua commands have no id, a cancel without one must not match them
+  if type(id) ~= "number" then
+   send({type="command_error", error="cancel needs the id of a command"})
+   return
+  end
+  if running and running.id == id then
+   os.queueEvent("cancel_running", id)
+   return
+  end
+  for i, command in ipairs(queue) do
+   if command.id == id then
+    table.remove(queue, i)
+    send({type="command_error", id=id, error="cancelled"})
+    send_queue()
+    return
    end
   end
+  send({type="command_error", id=id, error="no command " .. tostring(id) .. " in the queue"})
+ elseif kind == "clear" then
+  for _, command in ipairs(queue) do
+   send({type="command_error", id=command.id, error="cancelled"})
+  end
+  queue = {}
+  send_queue()
+ else
+  -- Commands arrive as {"type":"command","id":1,"code":"..."}, anything else is run as raw Lua
+  local command = {code=msg}
+  if kind == "command" then command = {id=request["id"], code=request["code"]} end
+  queue[#queue+1] = command
+  send_queue()
+  os.queueEvent("command_queued")
  end
+end
+
+function listen()
+ -- Commands may start timers of their own, only this one triggers scans
+ local scan_timer = os.startTimer(0.5)
  
- if event == "timer" then 
-  local data = geo.scan(RADIUS)
-  local blocks, pos = serialize(data)
-  ws.send("2" .. textutils.serializeJSON(blocks) .. textutils.serializeJSON(pos))
+ while true do
+  local event, p1, p2 = os.pullEvent()
+  if event == "websocket_message" and p2 then
+   receive(p2)
+  end
   
-  os.startTimer(2)
+  if event == "timer" and p1 == scan_timer then
+   send({type="heartbeat"})
+   send_inventory()
+   
+   if geo then
+    local origin = {pose.x, pose.y, pose.z}
+    local data = geo.scan(RADIUS)
+    local blocks, pos = serialize(data)
+    send({type="sparse_scan", radius=RADIUS, origin=origin, names=blocks, blocks=pos})
+   end
+   
+   scan_timer = os.startTimer(2)
+  end
  end
 end
+
+parallel.waitForAll(listen, executor)
@@ -0,0 +1,144 @@
+use std::collections::{HashSet, VecDeque};
+
+use macroquad::math::IVec3;
+use protocol::{CommandId, Value};
+
+use crate::{
+    commands::{CommandState, CommandTable},
+    pathfinding::{find_path, Action, PathRequest},
+    pose::TurtlePose,
+    world::World,
+};
+
+/// Replanning after this many failed moves means the known world is too far off.
+const MAX_REPLANS: u32 = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AutopilotStatus {
+    Running,
+    Arrived,
+    Failed(String),
+}
+
+/// Drives a turtle to `goal` one command at a time, replanning when a move fails.
+pub struct Autopilot {
+    pub goal: IVec3,
+    pub allow_digging: bool,
+    pub status: AutopilotStatus,
+    plan: VecDeque<Action>,
+    /// The command in flight and the pose it was issued from.
+    command: Option<(CommandId, Action, TurtlePose)>,
+    blocked: HashSet<IVec3>,
+    replans: u32,
+}
+
+impl Autopilot {
+    pub fn new(goal: IVec3, allow_digging: bool) -> Self {
+        Self {
+            goal,
+            allow_digging,
+            status: AutopilotStatus::Running,
+            plan: VecDeque::new(),
+            command: None,
+            blocked: HashSet::new(),
+            replans: 0,
+        }
+    }
+
+    /// The blocks the turtle will pass through from `pose` if the plan works out.
+    pub fn waypoints(&self, pose: &TurtlePose) -> Vec<IVec3> {
+        let mut position = pose.position;
+        let mut heading = pose.heading;
+        let mut waypoints = vec![position];
+
+        for action in self.plan.iter() {
+            (position, heading) = action.apply(position, heading);
+            if waypoints.last() != Some(&position) {
+                waypoints.push(position);
+            }
+        }
+
+        waypoints
+    }
+
+    /// Issues the next action once the previous one has been answered.
+    pub fn update(
+        &mut self,
+        pose: Option<TurtlePose>,
+        world: &World,
+        commands: &mut CommandTable,
+    ) -> &AutopilotStatus {
+        if self.status == AutopilotStatus::Running {
+            self.status = self.step(pose, world, commands);
+        }
+
+        &self.status
+    }
+
+    fn step(
+        &mut self,
+        pose: Option<TurtlePose>,
+        world: &World,
+        commands: &mut CommandTable,
+    ) -> AutopilotStatus {
+        let Some(pose) = pose else {
+            return AutopilotStatus::Failed("the turtle has not reported its position".to_owned());
+        };
+
+        if let Some((id, action, from)) = self.command {
+            match commands.get(id).map(|command| &command.state) {
+                Some(CommandState::Queued | CommandState::Pending | CommandState::Running) => {
+                    return AutopilotStatus::Running
+                }
+                Some(CommandState::Succeeded(values)) => {
+                    // turtle.forward() and friends return false instead of raising
+                    let refused = values.first() == Some(&Value::Bool(false));
+                    if let (true, Some(destination)) =
+                        (refused, action.destination(from.position, from.heading))
+                    {
+                        log::warn!("Could not move into {}, replanning", destination);
+                        self.blocked.insert(destination);
+                        self.plan.clear();
+                    }
+                }
+                Some(CommandState::Failed(error)) => {
+                    return AutopilotStatus::Failed(format!("`{}` failed: {}", action.lua(), error))
+                }
+                Some(CommandState::TimedOut) | None => {
+                    return AutopilotStatus::Failed(format!("`{}` got no reply", action.lua()))
+                }
+            }
+            self.command = None;
+        }
+
+        if pose.position == self.goal {
+            return AutopilotStatus::Arrived;
+        }
+
+        if self.plan.is_empty() {
+            if self.replans == MAX_REPLANS {
+                return AutopilotStatus::Failed("gave up after too many failed moves".to_owned());
+            }
+            self.replans += 1;
+
+            let request = PathRequest {
+                world,
+                start: pose,
+                goal: self.goal,
+                allow_digging: self.allow_digging,
+                blocked: &self.blocked,
+            };
+            match find_path(&request) {
+                Some(plan) => self.plan = plan.into(),
+                None => return AutopilotStatus::Failed(format!("no known path to {}", self.goal)),
+            }
+        }
+
+        if let Some(action) = self.plan.pop_front() {
+            let id = commands.issue(action.lua().to_owned());
+            self.command = Some((id, action, pose));
+        }
+
+        AutopilotStatus::Running
+    }
+}
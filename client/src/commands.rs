@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+
+use protocol::{CommandId, OperatorMessage, Value};
+
+/// Seconds to wait for a reply before a command is considered lost.
+pub const COMMAND_TIMEOUT: f64 = 10.;
+/// Finished commands kept around for inspection.
+const HISTORY_LENGTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CommandState {
+    /// Not sent yet.
+    Queued,
+    /// Sent, waiting in the turtle's queue or for its reply.
+    Pending,
+    /// The turtle reported it is running the command.
+    Running,
+    Succeeded(Vec<Value>),
+    Failed(String),
+    TimedOut,
+}
+
+impl CommandState {
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self,
+            CommandState::Queued | CommandState::Pending | CommandState::Running
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Command {
+    pub code: String,
+    pub sent_at: Option<f64>,
+    pub state: CommandState,
+}
+
+/// Every command for a turtle, keyed by the id the turtle echoes back.
+///
+/// Commands are sent as soon as they are issued, the turtle keeps its own
+/// queue and runs them one after the other.
+pub struct CommandTable {
+    next_id: CommandId,
+    pub commands: BTreeMap<CommandId, Command>,
+    /// The running command and those queued behind it, as the turtle last reported.
+    pub on_turtle: Vec<CommandId>,
+}
+
+impl CommandTable {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            commands: BTreeMap::new(),
+            on_turtle: vec![],
+        }
+    }
+
+    /// Queues `code` and returns the id its reply will carry.
+    pub fn issue(&mut self, code: String) -> CommandId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        self.commands.insert(
+            id,
+            Command {
+                code,
+                sent_at: None,
+                state: CommandState::Queued,
+            },
+        );
+        self.trim_history();
+
+        id
+    }
+
+    /// Marks every queued command as pending and returns the frames to send.
+    pub fn dispatch(&mut self, now: f64) -> Vec<(CommandId, String)> {
+        self.commands
+            .iter_mut()
+            .filter(|(_, command)| command.state == CommandState::Queued)
+            .map(|(id, command)| {
+                command.state = CommandState::Pending;
+                command.sent_at = Some(now);
+
+                let frame = OperatorMessage::Command {
+                    id: *id,
+                    code: command.code.clone(),
+                }
+                .encode();
+                (*id, frame)
+            })
+            .collect()
+    }
+
+    /// Drops every command that has not been sent yet.
+    pub fn clear_queue(&mut self) {
+        self.commands
+            .retain(|_, command| command.state != CommandState::Queued);
+    }
+
+    /// Cancels a command, returning the frame that asks the turtle to drop
+    /// it if it was already sent.
+    pub fn cancel(&mut self, id: CommandId) -> Option<String> {
+        let command = self.commands.get_mut(&id)?;
+        match command.state {
+            CommandState::Queued => {
+                command.state = CommandState::Failed("cancelled".to_owned());
+                None
+            }
+            CommandState::Pending | CommandState::Running => {
+                Some(OperatorMessage::Cancel { id }.encode())
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes in the queue the turtle reported.
+    pub fn report_queue(&mut self, running: Option<CommandId>, queued: Vec<CommandId>) {
+        if let Some(command) = running.and_then(|id| self.commands.get_mut(&id)) {
+            if command.state == CommandState::Pending {
+                command.state = CommandState::Running;
+            }
+        }
+
+        self.on_turtle = running.into_iter().chain(queued).collect();
+    }
+
+    /// Stores the turtle's reply, returning the command it belongs to.
+    pub fn resolve(&mut self, id: CommandId, state: CommandState) -> Option<&Command> {
+        let command = self.commands.get_mut(&id)?;
+        if command.state == CommandState::TimedOut {
+            log::warn!("Reply to command {} arrived after it timed out", id);
+        }
+        command.state = state;
+
+        Some(command)
+    }
+
+    /// Marks commands that have waited longer than [`COMMAND_TIMEOUT`] as
+    /// timed out, unless the turtle reported it still has them.
+    pub fn expire(&mut self, now: f64) -> Vec<CommandId> {
+        let mut expired = vec![];
+
+        for (id, command) in self.commands.iter_mut() {
+            let waited = now - command.sent_at.unwrap_or(now);
+            let waiting = matches!(command.state, CommandState::Pending | CommandState::Running);
+            if waiting && !self.on_turtle.contains(id) && waited > COMMAND_TIMEOUT {
+                command.state = CommandState::TimedOut;
+                expired.push(*id);
+            }
+        }
+
+        expired
+    }
+
+    pub fn get(&self, id: CommandId) -> Option<&Command> {
+        self.commands.get(&id)
+    }
+
+    /// Commands sent and not answered yet, running or not.
+    pub fn pending(&self) -> impl Iterator<Item = (&CommandId, &Command)> {
+        self.commands.iter().filter(|(_, command)| {
+            matches!(command.state, CommandState::Pending | CommandState::Running)
+        })
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = (&CommandId, &Command)> {
+        self.commands
+            .iter()
+            .filter(|(_, command)| command.state == CommandState::Queued)
+    }
+
+    fn trim_history(&mut self) {
+        while self.commands.len() > HISTORY_LENGTH {
+            let oldest_finished = self
+                .commands
+                .iter()
+                .find(|(_, command)| command.state.is_finished())
+                .map(|(id, _)| *id);
+
+            match oldest_finished {
+                Some(id) => self.commands.remove(&id),
+                None => break,
+            };
+        }
+    }
+}
+
+impl Default for CommandTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_matched_by_id() {
+        let mut table = CommandTable::new();
+        let forward = table.issue("turtle.forward()".to_string());
+        let back = table.issue("turtle.back()".to_string());
+
+        table.resolve(
+            back,
+            CommandState::Failed("Movement obstructed".to_string()),
+        );
+        table.resolve(forward, CommandState::Succeeded(vec![Value::Bool(true)]));
+
+        assert_eq!(
+            table.get(forward).unwrap().state,
+            CommandState::Succeeded(vec![Value::Bool(true)])
+        );
+        assert_eq!(
+            table.get(back).unwrap().state,
+            CommandState::Failed("Movement obstructed".to_string())
+        );
+        assert!(table.resolve(1234, CommandState::TimedOut).is_none());
+    }
+
+    #[test]
+    fn pending_commands_time_out() {
+        let mut table = CommandTable::new();
+        let id = table.issue("turtle.forward()".to_string());
+        table.dispatch(0.);
+
+        assert!(table.expire(COMMAND_TIMEOUT / 2.).is_empty());
+        assert_eq!(table.expire(COMMAND_TIMEOUT + 1.), vec![id]);
+        assert_eq!(table.pending().count(), 0);
+    }
+
+    #[test]
+    fn everything_queued_is_sent_at_once() {
+        let mut table = CommandTable::new();
+        let forward = table.issue("turtle.forward()".to_string());
+        let back = table.issue("turtle.back()".to_string());
+
+        let frames = table.dispatch(0.);
+        assert_eq!(
+            frames.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![forward, back]
+        );
+        assert_eq!(
+            OperatorMessage::decode(&frames[1].1).unwrap(),
+            OperatorMessage::Command {
+                id: back,
+                code: "turtle.back()".to_string()
+            }
+        );
+        assert!(table.dispatch(0.).is_empty());
+        assert_eq!(table.pending().count(), 2);
+    }
+
+    #[test]
+    fn the_turtle_reports_its_queue() {
+        let mut table = CommandTable::new();
+        let forward = table.issue("turtle.forward()".to_string());
+        let back = table.issue("turtle.back()".to_string());
+        table.dispatch(0.);
+
+        table.report_queue(Some(forward), vec![back]);
+        assert_eq!(table.get(forward).unwrap().state, CommandState::Running);
+        assert_eq!(table.get(back).unwrap().state, CommandState::Pending);
+        // Both are still on the turtle, however long it takes
+        assert!(table.expire(COMMAND_TIMEOUT * 10.).is_empty());
+
+        assert_eq!(
+            table
+                .cancel(back)
+                .map(|frame| OperatorMessage::decode(&frame).unwrap()),
+            Some(OperatorMessage::Cancel { id: back })
+        );
+        let unsent = table.issue("turtle.up()".to_string());
+        assert_eq!(table.cancel(unsent), None);
+        assert_eq!(
+            table.get(unsent).unwrap().state,
+            CommandState::Failed("cancelled".to_owned())
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Client settings from an optional TOML file, overridden by command line flags.
+//!
+//! ```toml
+//! port = 1235
+//! world = "world.ccws"
+//! save_interval = 30.0
+//! scan_radius = 8
+//! textures = "resourcepack.zip"
+//! rules = "rules.toml"
+//!
+//! [window]
+//! width = 1920
+//! height = 1080
+//! fullscreen = false
+//! ```
+
+use std::{fmt, path::PathBuf};
+
+use clap::Parser;
+use macroquad::prelude::Conf;
+use serde::Deserialize;
+
+#[derive(Parser, Debug, Default)]
+#[command(about = "3D viewer and remote control for ComputerCraft turtles")]
+pub struct Args {
+    /// TOML file to read settings from, the flags below take precedence
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
+    /// Port turtles connect to
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// World file to load on start and save the explored world to
+    #[arg(long)]
+    pub world: Option<PathBuf>,
+
+    /// Seconds between saves of the world file
+    #[arg(long)]
+    pub save_interval: Option<f64>,
+
+    /// Layers above and below the active turtle that scrolling slices through
+    #[arg(long)]
+    pub scan_radius: Option<u16>,
+
+    /// Directory of block PNGs or a resource pack zip to texture blocks with
+    #[arg(long)]
+    pub textures: Option<PathBuf>,
+
+    /// TOML file of color and visibility rules per block name
+    #[arg(long)]
+    pub rules: Option<PathBuf>,
+
+    #[arg(long)]
+    pub width: Option<i32>,
+
+    #[arg(long)]
+    pub height: Option<i32>,
+
+    #[arg(long, overrides_with = "no_fullscreen")]
+    pub fullscreen: bool,
+
+    /// Open a window even if the config file asks for fullscreen
+    #[arg(long, overrides_with = "fullscreen")]
+    pub no_fullscreen: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub world: PathBuf,
+    pub save_interval: f64,
+    pub scan_radius: u16,
+    /// Blocks are tinted stone without textures.
+    pub textures: Option<PathBuf>,
+    /// See [`crate::rules`] for the format.
+    pub rules: Option<PathBuf>,
+    pub window: WindowConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: i32,
+    pub height: i32,
+    pub fullscreen: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            // The server takes 1234
+            port: 1235,
+            world: PathBuf::from("world.ccws"),
+            save_interval: 30.,
+            scan_radius: 8,
+            textures: None,
+            rules: None,
+            window: WindowConfig::default(),
+        }
+    }
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1260,
+            height: 768,
+            fullscreen: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "{} is not valid: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the file `args` points at, if any, then applies the flags on top.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Read(path.clone(), err))?;
+                toml::from_str(&text).map_err(|err| ConfigError::Parse(path.clone(), err))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(port) = args.port {
+            config.port = port;
+        }
+        if let Some(world) = args.world {
+            config.world = world;
+        }
+        if let Some(save_interval) = args.save_interval {
+            config.save_interval = save_interval;
+        }
+        if let Some(scan_radius) = args.scan_radius {
+            config.scan_radius = scan_radius;
+        }
+        if let Some(textures) = args.textures {
+            config.textures = Some(textures);
+        }
+        if let Some(rules) = args.rules {
+            config.rules = Some(rules);
+        }
+        if let Some(width) = args.width {
+            config.window.width = width;
+        }
+        if let Some(height) = args.height {
+            config.window.height = height;
+        }
+        if args.fullscreen {
+            config.window.fullscreen = true;
+        }
+        if args.no_fullscreen {
+            config.window.fullscreen = false;
+        }
+
+        Ok(config)
+    }
+
+    pub fn window_conf(&self) -> Conf {
+        Conf {
+            window_title: String::from("Macroquad"),
+            window_width: self.window.width,
+            window_height: self.window.height,
+            fullscreen: self.window.fullscreen,
+            ..Default::default()
+        }
+    }
+}
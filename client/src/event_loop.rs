@@ -1,22 +1,88 @@
+use std::{io, path::Path};
+
+use macroquad::{
+    input::{is_quit_requested, prevent_quit},
+    time::get_time,
+};
+
 use crate::{
+    config::Config,
     objects::{KeyboardEventHandler, VoxelCamera, VoxelUi},
     renderer::Renderer,
+    rules::Rules,
+    save::{load_world, save_world},
     sockets::Sockets,
+    textures::BlockTextures,
+    world::World,
 };
 
-pub async fn run() {
-    let mut sockets = Sockets::new();
+pub async fn run(config: Config) {
+    let mut sockets = Sockets::listen(config.port);
     let mut camera = VoxelCamera::new();
     let mut ui_handler = VoxelUi::new();
-    let mut keyboard_events = KeyboardEventHandler::new();
+    let mut keyboard_events = KeyboardEventHandler::new(config.scan_radius);
     let mut renderer = Renderer::new();
 
+    match load_world(&config.world) {
+        Ok(world) => {
+            log::info!("Loaded world from {}", config.world.display());
+            renderer.world = world;
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log::info!("Starting a new world in {}", config.world.display());
+        }
+        Err(err) => {
+            log::error!("Could not load world {}: {}", config.world.display(), err);
+            // Keep the file for whoever wants to recover it, the next save would overwrite it
+            let mut aside = config.world.clone().into_os_string();
+            aside.push(".bad");
+            if let Err(err) = std::fs::rename(&config.world, &aside) {
+                log::error!("Could not move it out of the way, exiting: {}", err);
+                return;
+            }
+            log::info!(
+                "Moved it to {} and started a new world",
+                Path::new(&aside).display()
+            );
+        }
+    }
+    if let Some(path) = &config.textures {
+        match BlockTextures::load(path) {
+            Ok(textures) => {
+                log::info!(
+                    "Loaded {} block textures from {}",
+                    textures.len(),
+                    path.display()
+                );
+                renderer.set_textures(textures);
+            }
+            Err(err) => log::error!("Could not load textures from {}: {}", path.display(), err),
+        }
+    }
+    if let Some(path) = &config.rules {
+        match Rules::load(path) {
+            Ok(rules) => {
+                log::info!("Loaded {} rules from {}", rules.rules.len(), path.display());
+                renderer.rules = rules;
+                renderer.rules_changed();
+            }
+            Err(err) => log::error!("{}", err),
+        }
+    }
+    let mut last_save = get_time();
+    // Closing the window goes through the same save as the close key
+    prevent_quit();
+
     loop {
-        sockets.process(&mut renderer.blocks);
+        sockets.process(&mut renderer.world, get_time());
+        if let Some(turtle) = sockets.active_turtle() {
+            renderer.slice_origin = (turtle.position().y - config.scan_radius as i32) as f32;
+        }
+        renderer.update_turtles(&sockets);
 
         camera.process();
-        if camera.locked {
-            ui_handler.process(&sockets);
+        if camera.locked && ui_handler.process(&mut sockets, &mut renderer.rules) {
+            renderer.rules_changed();
         }
 
         if KeyboardEventHandler::should_grab() {
@@ -31,6 +97,36 @@ pub async fn run() {
             ui_handler.text = String::new();
         }
 
+        // Sends the turtle into the air block in front of the face under the crosshair
+        if KeyboardEventHandler::should_go_to(&camera) {
+            match renderer.pick(&camera) {
+                Some(hit) => sockets.go_to(hit.coord + hit.normal, ui_handler.allow_digging),
+                None => log::warn!("No block under the crosshair to go to"),
+            }
+        }
+
+        if renderer.world.modified && get_time() - last_save > config.save_interval {
+            save(&mut renderer.world, &config.world);
+            last_save = get_time();
+        }
+
+        if KeyboardEventHandler::should_close_app() || is_quit_requested() {
+            if renderer.world.modified {
+                save(&mut renderer.world, &config.world);
+            }
+            return;
+        }
+
         renderer.draw(&camera, &keyboard_events).await;
     }
 }
+
+fn save(world: &mut World, path: &Path) {
+    match save_world(world, path) {
+        Ok(()) => {
+            world.modified = false;
+            log::info!("Saved world to {}", path.display());
+        }
+        Err(err) => log::error!("Could not save world to {}: {}", path.display(), err),
+    }
+}
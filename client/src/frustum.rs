@@ -0,0 +1,49 @@
+use macroquad::math::{Mat4, Vec3, Vec4};
+
+/// The volume a camera sees, as six planes facing inwards.
+pub struct Frustum {
+    planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Planes of a combined projection and view matrix with OpenGL's clip
+    /// space, like [`macroquad::camera::Camera::matrix`] returns.
+    pub fn from_matrix(matrix: Mat4) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|row| matrix.row(row));
+
+        Self {
+            planes: [w + x, w - x, w + y, w - y, w + z, w - z],
+        }
+    }
+
+    /// Whether any part of the box between `min` and `max` may be visible.
+    /// Boxes near a corner of the frustum can pass without being on screen.
+    pub fn contains_box(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner = Vec3::select(plane.truncate().cmpge(Vec3::ZERO), max, min);
+
+            plane.truncate().dot(corner) + plane.w >= 0.
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxes_behind_or_beside_the_camera_are_culled() {
+        let projection = Mat4::perspective_rh_gl(1., 1., 0.01, 100.);
+        let view = Mat4::look_at_rh(Vec3::ZERO, Vec3::NEG_Z, Vec3::Y);
+        let frustum = Frustum::from_matrix(projection * view);
+
+        let cube = |center: Vec3| frustum.contains_box(center - 0.5, center + 0.5);
+        assert!(cube(Vec3::new(0., 0., -10.)));
+        assert!(!cube(Vec3::new(0., 0., 10.)));
+        assert!(!cube(Vec3::new(50., 0., -10.)));
+        assert!(!cube(Vec3::new(0., 0., -200.)));
+        // Partly on screen
+        assert!(cube(Vec3::new(5.3, 0., -10.)));
+    }
+}
@@ -0,0 +1,55 @@
+use protocol::ItemStack;
+
+/// Fuel and inventory, as last reported by the turtle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TurtleInventory {
+    /// `None` when the server does not use fuel.
+    pub fuel: Option<u32>,
+    pub fuel_limit: Option<u32>,
+    /// 1-based like `turtle.select`.
+    pub selected: u8,
+    pub slots: Vec<Option<ItemStack>>,
+}
+
+impl TurtleInventory {
+    pub fn fuel_text(&self) -> String {
+        match (self.fuel, self.fuel_limit) {
+            (Some(fuel), Some(limit)) => format!("Fuel: {} / {}", fuel, limit),
+            (Some(fuel), None) => format!("Fuel: {}", fuel),
+            (None, _) => "Fuel: unlimited".to_owned(),
+        }
+    }
+
+    pub fn slot(&self, slot: u8) -> Option<&ItemStack> {
+        self.slots.get(usize::from(slot).checked_sub(1)?)?.as_ref()
+    }
+
+    pub fn selected_item(&self) -> Option<&ItemStack> {
+        self.slot(self.selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_one_based() {
+        let coal = ItemStack {
+            name: "minecraft:coal".to_owned(),
+            count: 3,
+        };
+        let inventory = TurtleInventory {
+            fuel: Some(80),
+            fuel_limit: Some(20000),
+            selected: 2,
+            slots: vec![None, Some(coal.clone())],
+        };
+
+        assert_eq!(inventory.selected_item(), Some(&coal));
+        assert_eq!(inventory.slot(1), None);
+        assert_eq!(inventory.slot(0), None);
+        assert_eq!(inventory.slot(16), None);
+        assert_eq!(inventory.fuel_text(), "Fuel: 80 / 20000");
+    }
+}
@@ -0,0 +1,92 @@
+use std::f32::consts::{PI, TAU};
+
+use macroquad::prelude::*;
+
+use crate::pose::TurtlePose;
+
+/// Blocks per second, a turtle takes 0.4 seconds per move.
+const MOVE_SPEED: f32 = 2.5;
+/// Radians per second, a turtle takes 0.4 seconds per turn.
+const TURN_SPEED: f32 = PI / 2. / 0.4;
+/// Pose jumps longer than this, like a first GPS fix, are not animated.
+const SNAP_DISTANCE: f32 = 8.;
+
+/// A turtle drawn in the 3D view, easing towards its latest reported pose.
+pub struct TurtleMarker {
+    pub name: String,
+    pub pose: TurtlePose,
+    pub active: bool,
+    /// Blocks the turtle's autopilot plans to move through, empty when it has none.
+    pub path: Vec<IVec3>,
+    /// Center of the drawn model.
+    position: Vec3,
+    /// Angle around Y of the drawn model, 0 facing +Z.
+    yaw: f32,
+}
+
+impl TurtleMarker {
+    pub fn new(name: String, pose: TurtlePose) -> Self {
+        Self {
+            name,
+            pose,
+            active: false,
+            path: vec![],
+            position: Self::center(&pose),
+            yaw: Self::target_yaw(&pose),
+        }
+    }
+
+    fn center(pose: &TurtlePose) -> Vec3 {
+        pose.position.as_vec3() + 0.5
+    }
+
+    fn target_yaw(pose: &TurtlePose) -> f32 {
+        let forward = pose.forward();
+
+        (forward.x as f32).atan2(forward.z as f32)
+    }
+
+    pub fn animate(&mut self, delta: f32) {
+        let target = Self::center(&self.pose);
+        let offset = target - self.position;
+        if offset.length() > SNAP_DISTANCE {
+            self.position = target;
+        } else {
+            self.position += offset.clamp_length_max(MOVE_SPEED * delta);
+        }
+
+        // Turn the short way round
+        let turn = (Self::target_yaw(&self.pose) - self.yaw + PI).rem_euclid(TAU) - PI;
+        self.yaw += turn.clamp(-TURN_SPEED * delta, TURN_SPEED * delta);
+    }
+
+    pub fn draw(&self) {
+        let forward = vec3(self.yaw.sin(), 0., self.yaw.cos());
+        let right = forward.cross(Vec3::Y);
+        let color = if self.active { GOLD } else { GRAY };
+
+        draw_cube(self.position, Vec3::splat(0.8), None, color);
+        draw_cube_wires(self.position, Vec3::splat(0.8), DARKGRAY);
+
+        // The turtle's face
+        draw_cube(
+            self.position + forward * 0.4,
+            vec3(0.3, 0.3, 0.3),
+            None,
+            DARKGREEN,
+        );
+
+        // Arrow in the facing direction
+        let tip = self.position + forward * 1.3;
+        draw_line_3d(self.position, tip, RED);
+        draw_line_3d(tip, tip - forward * 0.3 + right * 0.2, RED);
+        draw_line_3d(tip, tip - forward * 0.3 - right * 0.2, RED);
+
+        for step in self.path.windows(2) {
+            draw_line_3d(step[0].as_vec3() + 0.5, step[1].as_vec3() + 0.5, BLUE);
+        }
+        if let Some(goal) = self.path.last() {
+            draw_cube_wires(goal.as_vec3() + 0.5, Vec3::splat(1.02), BLUE);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Greedy meshing: faces of the same block type that lie next to each other
+//! in the same plane and face the same way are merged into a single quad.
+
+use macroquad::math::{vec2, IVec3, Vec2, Vec3};
+
+use crate::world::{BlockId, Chunk, World, AIR, CHUNK_SIZE};
+
+/// Face normals in the order `Renderer` has always numbered the faces of a
+/// block: front, back, top, bottom, right, left.
+pub const FACES: [IVec3; 6] = [
+    IVec3::Z,
+    IVec3::NEG_Z,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::X,
+    IVec3::NEG_X,
+];
+
+/// A rectangle of block faces, `width` blocks along the first axis of
+/// [`face_axes`] and `height` along the second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quad {
+    pub normal: IVec3,
+    /// The corner with the lowest coordinates, on the plane the faces lie in.
+    pub origin: IVec3,
+    pub width: i32,
+    pub height: i32,
+    pub block: BlockId,
+}
+
+impl Quad {
+    /// Corners in order around the quad, with texture coordinates that
+    /// repeat the texture once per block.
+    pub fn corners(&self) -> [(Vec3, Vec2); 4] {
+        let (u, v) = face_axes(self.normal);
+        let origin = self.origin.as_vec3();
+        let u = u.as_vec3() * self.width as f32;
+        let v = v.as_vec3() * self.height as f32;
+        let (width, height) = (self.width as f32, self.height as f32);
+
+        [
+            (origin, vec2(0., 0.)),
+            (origin + u, vec2(width, 0.)),
+            (origin + u + v, vec2(width, height)),
+            (origin + v, vec2(0., height)),
+        ]
+    }
+}
+
+/// The two axes spanning the faces with `normal`.
+pub fn face_axes(normal: IVec3) -> (IVec3, IVec3) {
+    match normal.abs() {
+        IVec3::X => (IVec3::Z, IVec3::Y),
+        IVec3::Y => (IVec3::X, IVec3::Z),
+        _ => (IVec3::X, IVec3::Y),
+    }
+}
+
+/// Merged quads for every visible face of the blocks in the chunk at
+/// `chunk_coord`. Blocks for which `hidden` holds are left out, and the
+/// faces of their neighbours show through where they were. Faces next to
+/// blocks that are not `opaque` are kept too, unless both blocks are the same.
+pub fn chunk_quads(
+    world: &World,
+    chunk_coord: IVec3,
+    hidden: impl Fn(IVec3, BlockId) -> bool,
+    opaque: impl Fn(BlockId) -> bool,
+) -> Vec<Quad> {
+    let Some(chunk) = world.chunks.get(&chunk_coord) else {
+        return vec![];
+    };
+    let base = chunk_coord * CHUNK_SIZE;
+    let size = CHUNK_SIZE as usize;
+    let mut quads = vec![];
+
+    for normal in FACES {
+        let (u, v) = face_axes(normal);
+        let depth_axis = normal.abs();
+
+        for depth in 0..CHUNK_SIZE {
+            // The block each face in this slice belongs to, if the face can be seen
+            let mut mask: Vec<Option<BlockId>> = vec![None; size * size];
+            for j in 0..CHUNK_SIZE {
+                for i in 0..CHUNK_SIZE {
+                    let local = depth_axis * depth + u * i + v * j;
+                    let id = chunk.blocks[Chunk::index(local)];
+                    let coord = base + local;
+                    if id <= AIR || hidden(coord, id) {
+                        continue;
+                    }
+
+                    let neighbour = coord + normal;
+                    let neighbour_id = world.get(neighbour);
+                    let covered = neighbour_id > AIR
+                        && !hidden(neighbour, neighbour_id)
+                        && (opaque(neighbour_id) || neighbour_id == id);
+                    if covered {
+                        continue;
+                    }
+                    mask[j as usize * size + i as usize] = Some(id);
+                }
+            }
+
+            for j in 0..size {
+                let mut i = 0;
+                while i < size {
+                    let Some(id) = mask[j * size + i] else {
+                        i += 1;
+                        continue;
+                    };
+
+                    let mut width = 1;
+                    while i + width < size && mask[j * size + i + width] == Some(id) {
+                        width += 1;
+                    }
+                    let mut height = 1;
+                    while j + height < size
+                        && (0..width).all(|k| mask[(j + height) * size + i + k] == Some(id))
+                    {
+                        height += 1;
+                    }
+
+                    for row in j..j + height {
+                        mask[row * size + i..row * size + i + width].fill(None);
+                    }
+
+                    let block = base + depth_axis * depth + u * i as i32 + v * j as i32;
+                    // Faces pointing up an axis lie on the far side of their block
+                    let origin = if normal.max_element() > 0 {
+                        block + normal
+                    } else {
+                        block
+                    };
+                    quads.push(Quad {
+                        normal,
+                        origin,
+                        width: width as i32,
+                        height: height as i32,
+                        block: id,
+                    });
+
+                    i += width;
+                }
+            }
+        }
+    }
+
+    quads
+}
+
+#[cfg(test)]
+mod tests {
+    use macroquad::math::ivec3;
+
+    use super::*;
+
+    #[test]
+    fn a_floor_is_one_quad_per_side() {
+        let mut blocks = vec![];
+        for x in 0..3 {
+            for z in 0..3 {
+                blocks.push((ivec3(x, 0, z), "minecraft:stone"));
+            }
+        }
+        let world = World::with_blocks(&blocks);
+
+        let quads = chunk_quads(&world, IVec3::ZERO, |_, _| false, |_| true);
+        assert_eq!(quads.len(), 6);
+        let top = quads.iter().find(|quad| quad.normal == IVec3::Y).unwrap();
+        assert_eq!((top.origin, top.width, top.height), (ivec3(0, 1, 0), 3, 3));
+        let left = quads
+            .iter()
+            .find(|quad| quad.normal == IVec3::NEG_X)
+            .unwrap();
+        assert_eq!(
+            (left.origin, left.width, left.height),
+            (ivec3(0, 0, 0), 3, 1)
+        );
+    }
+
+    #[test]
+    fn different_blocks_are_not_merged() {
+        let world = World::with_blocks(&[
+            (ivec3(0, 0, 0), "minecraft:stone"),
+            (ivec3(1, 0, 0), "minecraft:dirt"),
+        ]);
+
+        let tops: Vec<Quad> = chunk_quads(&world, IVec3::ZERO, |_, _| false, |_| true)
+            .into_iter()
+            .filter(|quad| quad.normal == IVec3::Y)
+            .collect();
+        assert_eq!(tops.len(), 2);
+        assert!(tops.iter().all(|quad| quad.width == 1 && quad.height == 1));
+    }
+
+    #[test]
+    fn hidden_blocks_uncover_their_neighbours() {
+        let world = World::with_blocks(&[
+            (ivec3(0, 0, 0), "minecraft:stone"),
+            (ivec3(0, 1, 0), "minecraft:stone"),
+        ]);
+
+        // Stacked, the shared faces are not drawn
+        assert_eq!(
+            chunk_quads(&world, IVec3::ZERO, |_, _| false, |_| true).len(),
+            6
+        );
+
+        let quads = chunk_quads(&world, IVec3::ZERO, |coord, _| coord.y >= 1, |_| true);
+        assert_eq!(quads.len(), 6);
+        assert!(quads
+            .iter()
+            .any(|quad| quad.normal == IVec3::Y && quad.origin == ivec3(0, 1, 0)));
+    }
+
+    #[test]
+    fn see_through_blocks_show_what_is_behind_them() {
+        let world = World::with_blocks(&[
+            (ivec3(0, 0, 0), "minecraft:stone"),
+            (ivec3(0, 1, 0), "minecraft:glass"),
+            (ivec3(0, 2, 0), "minecraft:glass"),
+        ]);
+        let glass = world
+            .palette
+            .iter()
+            .position(|block| block.name == "minecraft:glass");
+        let glass = glass.unwrap() as BlockId;
+
+        let quads = chunk_quads(&world, IVec3::ZERO, |_, _| false, |id| id != glass);
+        // The stone's top shows, the faces between the two glass blocks do not
+        assert!(quads
+            .iter()
+            .any(|quad| quad.normal == IVec3::Y && quad.origin == ivec3(0, 1, 0)));
+        assert!(!quads
+            .iter()
+            .any(|quad| quad.normal.y != 0 && quad.origin == ivec3(0, 2, 0)));
+    }
+
+    #[test]
+    fn corners_span_the_quad() {
+        let quad = Quad {
+            normal: IVec3::NEG_Z,
+            origin: ivec3(1, 2, 3),
+            width: 2,
+            height: 3,
+            block: 2,
+        };
+
+        let corners = quad.corners();
+        assert_eq!(corners[2].0, Vec3::new(3., 5., 3.));
+        assert_eq!(corners[2].1, vec2(2., 3.));
+    }
+}
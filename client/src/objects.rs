@@ -1,6 +1,16 @@
-use macroquad::{prelude::*, ui::widgets::Button};
+use macroquad::{
+    prelude::*,
+    ui::widgets::{Button, Checkbox, ComboBox},
+};
 
-use crate::{sockets::Sockets, SCAN_WIDTH, SCAN_WIDTH_SQUARED};
+use protocol::CommandId;
+
+use crate::{autopilot::AutopilotStatus, commands::CommandState, rules::Rules, sockets::Sockets};
+
+/// Commands the queue window has room for.
+const QUEUE_ROWS: usize = 8;
+/// Inventory slots of a turtle, shown four to a row.
+const SLOTS: u8 = 16;
 
 #[derive(Default)]
 pub struct VoxelCamera {
@@ -79,28 +89,38 @@ impl VoxelCamera {
             self.position.y -= self.move_speed;
         }
 
-        set_camera(&Camera3D {
+        set_camera(&self.camera());
+    }
+
+    /// The camera the world is drawn with.
+    pub fn camera(&self) -> Camera3D {
+        Camera3D {
             position: self.position,
             up: Vec3::Y,
-            target: self.position + front,
+            target: self.position + self.direction,
             ..Default::default()
-        });
+        }
     }
 }
 
 #[derive(Default)]
 pub struct VoxelUi {
     pub text: String,
+    /// Whether turtles sent somewhere with G may dig through blocks in the way.
+    pub allow_digging: bool,
 }
 
 impl VoxelUi {
     pub fn new() -> Self {
         Self {
             text: String::new(),
+            allow_digging: false,
         }
     }
 
-    pub fn process(&mut self, sockets: &Sockets) {
+    /// Draws the windows and acts on what was clicked, returning whether
+    /// any of the `rules` were toggled.
+    pub fn process(&mut self, sockets: &mut Sockets, rules: &mut Rules) -> bool {
         use macroquad::hash;
         use macroquad::ui::root_ui;
 
@@ -115,86 +135,256 @@ impl VoxelUi {
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.forward()".to_owned());
+                sockets.send_message("return turtle.forward()".to_owned());
             }
             if Button::new("Backward")
                 .position(vec2(88.0, 4.0))
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.back()".to_owned());
+                sockets.send_message("return turtle.back()".to_owned());
             }
             if Button::new("Turn Left")
                 .position(vec2(4.0, 40.0))
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.turnLeft()".to_owned());
+                sockets.send_message("return turtle.turnLeft()".to_owned());
             }
             if Button::new("Turn Right")
                 .position(vec2(88.0, 40.0))
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.turnRight()".to_owned());
+                sockets.send_message("return turtle.turnRight()".to_owned());
             }
             if Button::new("Up")
                 .position(vec2(4.0, 76.0))
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.up()".to_owned());
+                sockets.send_message("return turtle.up()".to_owned());
             }
             if Button::new("Down")
                 .position(vec2(88.0, 76.0))
                 .size(vec2(80., 32.))
                 .ui(ui)
             {
-                sockets.send_message("turtle.down()".to_owned());
+                sockets.send_message("return turtle.down()".to_owned());
             }
         });
         root_ui().pop_skin();
+
+        if !sockets.turtles.is_empty() {
+            let client_ids: Vec<u64> = sockets.turtles.keys().copied().collect();
+            let names: Vec<String> = sockets.turtles.values().map(|t| t.name()).collect();
+            let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+            let mut selected = client_ids
+                .iter()
+                .position(|client_id| Some(*client_id) == sockets.active)
+                .unwrap_or(0);
+
+            root_ui().window(hash!(), vec2(10., 170.), vec2(174., 30.), |ui| {
+                ComboBox::new(hash!(), &names).ui(ui, &mut selected);
+            });
+            root_ui().pop_skin();
+
+            if Some(client_ids[selected]) != sockets.active {
+                sockets.select(client_ids[selected]);
+            }
+        }
+
+        let status = sockets
+            .active_turtle()
+            .and_then(|turtle| turtle.autopilot.as_ref())
+            .map(|autopilot| match &autopilot.status {
+                AutopilotStatus::Running => format!("Going to {}", autopilot.goal),
+                AutopilotStatus::Arrived => format!("Arrived at {}", autopilot.goal),
+                AutopilotStatus::Failed(error) => format!("Failed: {}", error),
+            });
+
+        root_ui().window(hash!(), vec2(10., 206.), vec2(174., 74.), |ui| {
+            Checkbox::new(hash!())
+                .label("Allow digging")
+                .ui(ui, &mut self.allow_digging);
+            if Button::new("Stop")
+                .position(vec2(4.0, 24.0))
+                .size(vec2(80., 24.))
+                .ui(ui)
+            {
+                if let Some(turtle) = sockets.active_turtle_mut() {
+                    turtle.stop();
+                }
+            }
+            ui.label(
+                vec2(4.0, 52.0),
+                status.as_deref().unwrap_or("Press G to go to a block"),
+            );
+        });
+        root_ui().pop_skin();
+
+        Self::inventory_window(sockets);
+        Self::queue_window(sockets);
+        Self::rules_window(rules)
     }
-}
 
-#[derive(Clone)]
-pub struct Block {
-    pub name: String,
-    pub coord: Vec3,
-    pub color: Color,
-}
+    /// A row per rule, to turn it on and off and to hide what it matches.
+    fn rules_window(rules: &mut Rules) -> bool {
+        use macroquad::hash;
+        use macroquad::ui::root_ui;
 
-impl Block {
-    pub fn delinearize(block_index: u16) -> Vec3 {
-        let w = SCAN_WIDTH;
-        let r = block_index % (w * w);
+        if rules.rules.is_empty() {
+            return false;
+        }
 
-        let x = r % w;
-        let y = r / w;
-        let z = block_index / (w * w);
+        let before = rules.clone();
+        let height = 8. + rules.rules.len() as f32 * 24.;
+        root_ui().window(hash!(), vec2(370., 50.), vec2(260., height), |ui| {
+            for (row, rule) in rules.rules.iter_mut().enumerate() {
+                let y = 4. + row as f32 * 24.;
+                Checkbox::new(hash!(("rule enabled", row)))
+                    .label(&rule.blocks)
+                    .pos(vec2(4., y))
+                    .size(vec2(180., 20.))
+                    .ui(ui, &mut rule.enabled);
+                Checkbox::new(hash!(("rule hidden", row)))
+                    .label("Hide")
+                    .pos(vec2(190., y))
+                    .size(vec2(64., 20.))
+                    .ui(ui, &mut rule.hidden);
+            }
+        });
+        root_ui().pop_skin();
 
-        vec3(x as f32, y as f32, z as f32)
+        *rules != before
     }
 
-    pub fn linearize(coord: Vec3) -> u16 {
-        (coord.x + coord.y * SCAN_WIDTH as f32 + coord.z * SCAN_WIDTH_SQUARED as f32) as u16
+    /// Fuel and slots of the active turtle, with buttons to select a slot and
+    /// refuel from or drop the selected one.
+    fn inventory_window(sockets: &mut Sockets) {
+        use macroquad::hash;
+        use macroquad::ui::root_ui;
+
+        let Some(inventory) = sockets
+            .active_turtle()
+            .and_then(|turtle| turtle.inventory.clone())
+        else {
+            return;
+        };
+
+        let mut command = None;
+        root_ui().window(hash!(), vec2(190., 50.), vec2(174., 222.), |ui| {
+            ui.label(vec2(4.0, 4.0), &inventory.fuel_text());
+
+            for slot in 1..=SLOTS {
+                let index = f32::from(slot - 1);
+                let count = inventory
+                    .slot(slot)
+                    .map_or(String::new(), |item| item.count.to_string());
+                let label = if slot == inventory.selected {
+                    format!("[{}]", count)
+                } else {
+                    count
+                };
+
+                if Button::new(label)
+                    .position(vec2(
+                        4.0 + (index % 4.) * 42.,
+                        24.0 + (index / 4.).floor() * 36.,
+                    ))
+                    .size(vec2(38., 32.))
+                    .ui(ui)
+                {
+                    command = Some(format!("return turtle.select({})", slot));
+                }
+            }
+
+            let selected = inventory.selected_item();
+            ui.label(
+                vec2(4.0, 170.0),
+                selected.map_or("Empty slot", |item| item.name.as_str()),
+            );
+            if Button::new("Refuel")
+                .position(vec2(4.0, 192.0))
+                .size(vec2(80., 24.))
+                .ui(ui)
+            {
+                command = Some("return turtle.refuel()".to_owned());
+            }
+            if Button::new("Drop")
+                .position(vec2(88.0, 192.0))
+                .size(vec2(80., 24.))
+                .ui(ui)
+            {
+                command = Some("return turtle.drop()".to_owned());
+            }
+        });
+        root_ui().pop_skin();
+
+        if let Some(command) = command {
+            sockets.send_message(command);
+        }
     }
-}
 
-impl Default for Block {
-    fn default() -> Self {
-        Self {
-            name: "minecraft:air".to_string(),
-            coord: Vec3::ZERO,
-            color: GREEN,
+    /// The active turtle's commands that have not finished, in the order they run.
+    fn queue_window(sockets: &mut Sockets) {
+        use macroquad::hash;
+        use macroquad::ui::root_ui;
+
+        let Some(turtle) = sockets.active_turtle_mut() else {
+            return;
+        };
+        let rows: Vec<(CommandId, String)> = turtle
+            .commands
+            .commands
+            .iter()
+            .filter(|(_, command)| !command.state.is_finished())
+            .take(QUEUE_ROWS)
+            .map(|(id, command)| {
+                let marker = match command.state {
+                    CommandState::Running => ">",
+                    CommandState::Pending => "-",
+                    _ => " ",
+                };
+                (*id, format!("{} {}", marker, command.code))
+            })
+            .collect();
+
+        let mut cancel = None;
+        let mut clear = false;
+        root_ui().window(hash!(), vec2(10., 286.), vec2(174., 204.), |ui| {
+            clear = Button::new("Clear queue")
+                .position(vec2(4.0, 4.0))
+                .size(vec2(164., 24.))
+                .ui(ui);
+            for (row, (id, text)) in rows.iter().enumerate() {
+                let y = 32. + row as f32 * 20.;
+                if Button::new("x")
+                    .position(vec2(4.0, y))
+                    .size(vec2(18., 18.))
+                    .ui(ui)
+                {
+                    cancel = Some(*id);
+                }
+                ui.label(vec2(26.0, y), text);
+            }
+        });
+        root_ui().pop_skin();
+
+        if clear {
+            turtle.clear_queue();
+        }
+        if let Some(id) = cancel {
+            turtle.cancel(id);
         }
     }
 }
 
-#[derive(Default)]
 pub struct KeyboardEventHandler {
     pub mouse_grabbed: bool,
     pub scroll_index: f32,
+    /// Layers scrolling goes through, the scan width around the active turtle.
+    pub slice_layers: f32,
 }
 
 impl KeyboardEventHandler {
@@ -202,9 +392,10 @@ impl KeyboardEventHandler {
         is_mouse_button_down(MouseButton::Left)
     }
 
-    pub fn new() -> Self {
+    pub fn new(scan_radius: u16) -> Self {
         let mouse_grabbed = true;
-        let scroll_index = SCAN_WIDTH as f32;
+        let slice_layers = (2 * scan_radius + 1) as f32;
+        let scroll_index = slice_layers;
 
         set_cursor_grab(mouse_grabbed);
         show_mouse(false);
@@ -212,6 +403,7 @@ impl KeyboardEventHandler {
         KeyboardEventHandler {
             mouse_grabbed,
             scroll_index,
+            slice_layers,
         }
     }
 
@@ -219,7 +411,7 @@ impl KeyboardEventHandler {
         let mouse_wheel_index = mouse_wheel().1.clamp(-1., 1.);
 
         self.scroll_index += mouse_wheel_index;
-        self.scroll_index %= SCAN_WIDTH as f32;
+        self.scroll_index %= self.slice_layers;
     }
 
     pub fn should_close_app() -> bool {
@@ -234,6 +426,10 @@ impl KeyboardEventHandler {
         is_key_pressed(KeyCode::Enter) && camera.locked
     }
 
+    pub fn should_go_to(camera: &VoxelCamera) -> bool {
+        is_key_pressed(KeyCode::G) && !camera.locked
+    }
+
     pub fn switch_grab_mode(&mut self, camera: &mut VoxelCamera) {
         self.mouse_grabbed = !self.mouse_grabbed;
         camera.locked = !camera.locked;
@@ -0,0 +1,294 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use macroquad::math::{ivec3, IVec3};
+use protocol::Heading;
+
+use crate::{
+    pose::TurtlePose,
+    world::{World, AIR},
+};
+
+/// Extra cost of digging through a block on top of moving into it.
+pub const DIG_COST: u32 = 4;
+/// Gives up instead of stalling the frame when the goal is unreachable in a large world.
+const MAX_EXPANSIONS: usize = 200_000;
+
+const UNDIGGABLE: [&str; 2] = ["minecraft:bedrock", "minecraft:barrier"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Forward,
+    Back,
+    Up,
+    Down,
+    TurnLeft,
+    TurnRight,
+    Dig,
+    DigUp,
+    DigDown,
+}
+
+impl Action {
+    pub fn lua(self) -> &'static str {
+        match self {
+            Action::Forward => "return turtle.forward()",
+            Action::Back => "return turtle.back()",
+            Action::Up => "return turtle.up()",
+            Action::Down => "return turtle.down()",
+            Action::TurnLeft => "return turtle.turnLeft()",
+            Action::TurnRight => "return turtle.turnRight()",
+            Action::Dig => "return turtle.dig()",
+            Action::DigUp => "return turtle.digUp()",
+            Action::DigDown => "return turtle.digDown()",
+        }
+    }
+
+    /// The block this action moves the turtle into, if it moves at all.
+    pub fn destination(self, position: IVec3, heading: Heading) -> Option<IVec3> {
+        let (x, z) = heading.step();
+        let forward = ivec3(x, 0, z);
+
+        match self {
+            Action::Forward => Some(position + forward),
+            Action::Back => Some(position - forward),
+            Action::Up => Some(position + IVec3::Y),
+            Action::Down => Some(position - IVec3::Y),
+            _ => None,
+        }
+    }
+
+    /// Pose after this action succeeds.
+    pub fn apply(self, position: IVec3, heading: Heading) -> (IVec3, Heading) {
+        match self {
+            Action::TurnLeft => (position, heading.turn_left()),
+            Action::TurnRight => (position, heading.turn_right()),
+            _ => (
+                self.destination(position, heading).unwrap_or(position),
+                heading,
+            ),
+        }
+    }
+}
+
+pub struct PathRequest<'a> {
+    pub world: &'a World,
+    pub start: TurtlePose,
+    pub goal: IVec3,
+    pub allow_digging: bool,
+    /// Blocks a move has failed into, avoided even if the world says they are air.
+    pub blocked: &'a HashSet<IVec3>,
+}
+
+impl PathRequest<'_> {
+    fn is_open(&self, coord: IVec3) -> bool {
+        self.world.get(coord) == AIR && !self.blocked.contains(&coord)
+    }
+
+    fn is_diggable(&self, coord: IVec3) -> bool {
+        self.allow_digging
+            && !self.blocked.contains(&coord)
+            && self
+                .world
+                .solid_block(coord)
+                .is_some_and(|block| !UNDIGGABLE.contains(&block.name.as_str()))
+    }
+
+    /// Moving into `coord`, digging it out first if needed.
+    fn enter(&self, coord: IVec3, dig: Action, step: Action) -> Option<(u32, Vec<Action>)> {
+        if self.is_open(coord) {
+            Some((1, vec![step]))
+        } else if self.is_diggable(coord) {
+            Some((1 + DIG_COST, vec![dig, step]))
+        } else {
+            None
+        }
+    }
+
+    fn neighbours(&self, position: IVec3, heading: Heading) -> Vec<(u32, Vec<Action>)> {
+        let mut edges = vec![(1, vec![Action::TurnLeft]), (1, vec![Action::TurnRight])];
+
+        let forward = Action::Forward.destination(position, heading).unwrap();
+        edges.extend(self.enter(forward, Action::Dig, Action::Forward));
+        edges.extend(self.enter(position + IVec3::Y, Action::DigUp, Action::Up));
+        edges.extend(self.enter(position - IVec3::Y, Action::DigDown, Action::Down));
+
+        // Turtles can not dig behind themselves
+        if self.is_open(position - (forward - position)) {
+            edges.push((1, vec![Action::Back]));
+        }
+
+        edges
+    }
+}
+
+/// A* over (position, heading) through known air, optionally digging through
+/// solid blocks. Returns the turtle actions that reach `goal`, `None` if it can
+/// not be reached through the known world.
+pub fn find_path(request: &PathRequest) -> Option<Vec<Action>> {
+    type State = (IVec3, Heading);
+
+    let heuristic = |position: IVec3| (position - request.goal).abs().element_sum() as u32;
+    let start: State = (request.start.position, request.start.heading);
+
+    // Heap entries point into `states`, later states win ties on the estimate
+    let mut states: Vec<State> = vec![start];
+    let mut open = BinaryHeap::from([Reverse((heuristic(start.0), 0u32, Reverse(0usize)))]);
+    let mut best: HashMap<State, u32> = HashMap::from([(start, 0)]);
+    let mut came_from: HashMap<State, (State, Vec<Action>)> = HashMap::new();
+
+    while let Some(Reverse((_, cost, Reverse(index)))) = open.pop() {
+        let state = states[index];
+        if state.0 == request.goal {
+            let mut actions = vec![];
+            let mut current = state;
+            while let Some((previous, edge)) = came_from.get(&current) {
+                actions.splice(0..0, edge.iter().copied());
+                current = *previous;
+            }
+
+            return Some(actions);
+        }
+        if cost > best[&state] {
+            continue;
+        }
+        if best.len() > MAX_EXPANSIONS {
+            log::warn!(
+                "Gave up pathfinding to {} after {} states",
+                request.goal,
+                best.len()
+            );
+            return None;
+        }
+
+        for (edge_cost, edge) in request.neighbours(state.0, state.1) {
+            let next = edge.iter().fold(state, |(position, heading), action| {
+                action.apply(position, heading)
+            });
+            let next_cost = cost + edge_cost;
+
+            if best.get(&next).is_some_and(|best| *best <= next_cost) {
+                continue;
+            }
+            best.insert(next, next_cost);
+            came_from.insert(next, (state, edge));
+            states.push(next);
+            open.push(Reverse((
+                next_cost + heuristic(next.0),
+                next_cost,
+                Reverse(states.len() - 1),
+            )));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::PositionSource;
+
+    fn world_with_floor() -> World {
+        let mut world = World::new();
+        let stone = world.block_id("minecraft:stone");
+        for x in -5..=5 {
+            for z in -5..=5 {
+                world.set(ivec3(x, -1, z), stone, 1);
+                for y in 0..3 {
+                    world.set(ivec3(x, y, z), AIR, 1);
+                }
+            }
+        }
+
+        world
+    }
+
+    fn start() -> TurtlePose {
+        TurtlePose {
+            position: IVec3::ZERO,
+            heading: Heading::North,
+            source: PositionSource::Gps,
+        }
+    }
+
+    fn walk(actions: &[Action]) -> IVec3 {
+        actions
+            .iter()
+            .fold(
+                (IVec3::ZERO, Heading::North),
+                |(position, heading), action| action.apply(position, heading),
+            )
+            .0
+    }
+
+    #[test]
+    fn walks_straight_ahead() {
+        let world = world_with_floor();
+        let blocked = HashSet::new();
+        let request = PathRequest {
+            world: &world,
+            start: start(),
+            goal: ivec3(0, 0, -3),
+            allow_digging: false,
+            blocked: &blocked,
+        };
+
+        assert_eq!(find_path(&request).unwrap(), vec![Action::Forward; 3]);
+    }
+
+    #[test]
+    fn goes_around_walls_unless_digging() {
+        let mut world = world_with_floor();
+        let stone = world.block_id("minecraft:stone");
+        for x in -5..=5 {
+            for y in 0..3 {
+                if x != 4 {
+                    world.set(ivec3(x, y, -1), stone, 1);
+                }
+            }
+        }
+        let blocked = HashSet::new();
+        let mut request = PathRequest {
+            world: &world,
+            start: start(),
+            goal: ivec3(0, 0, -2),
+            allow_digging: false,
+            blocked: &blocked,
+        };
+
+        let detour = find_path(&request).unwrap();
+        assert_eq!(walk(&detour), ivec3(0, 0, -2));
+        assert!(!detour.contains(&Action::Dig));
+
+        request.allow_digging = true;
+        assert_eq!(
+            find_path(&request).unwrap(),
+            vec![Action::Dig, Action::Forward, Action::Forward]
+        );
+    }
+
+    #[test]
+    fn unknown_blocks_and_failed_moves_are_avoided() {
+        let world = world_with_floor();
+        let blocked = HashSet::from([ivec3(0, 0, -1)]);
+        let request = PathRequest {
+            world: &world,
+            start: start(),
+            goal: ivec3(0, 0, -8),
+            allow_digging: true,
+            blocked: &blocked,
+        };
+        assert!(find_path(&request).is_none());
+
+        let request = PathRequest {
+            goal: ivec3(0, 0, -2),
+            ..request
+        };
+        let path = find_path(&request).unwrap();
+        assert_eq!(walk(&path), ivec3(0, 0, -2));
+        assert!(path.len() > 2);
+    }
+}
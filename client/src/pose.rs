@@ -0,0 +1,19 @@
+use macroquad::math::{ivec3, IVec3};
+use protocol::{Heading, PositionSource};
+
+/// Where a turtle is and which way it faces, as last reported by the turtle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurtlePose {
+    pub position: IVec3,
+    pub heading: Heading,
+    pub source: PositionSource,
+}
+
+impl TurtlePose {
+    /// Unit vector pointing the way the turtle faces.
+    pub fn forward(&self) -> IVec3 {
+        let (x, z) = self.heading.step();
+
+        ivec3(x, 0, z)
+    }
+}
@@ -0,0 +1,122 @@
+//! Grid traversal after Amanatides and Woo, "A Fast Voxel Traversal Algorithm
+//! for Ray Tracing": the ray visits every block it passes through in order,
+//! so finding the first solid one costs one step per block crossed.
+
+use macroquad::math::{IVec3, Vec3};
+
+use crate::world::{BlockId, World};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    pub coord: IVec3,
+    pub block: BlockId,
+    /// Normal of the face the ray entered through, so `coord + normal` is the
+    /// block in front of that face.
+    pub normal: IVec3,
+}
+
+/// The first solid block along the ray within `max_distance`, skipping the
+/// block the ray starts in and blocks for which `hidden` holds.
+pub fn raycast(
+    world: &World,
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    hidden: impl Fn(IVec3) -> bool,
+) -> Option<RayHit> {
+    let direction = direction.try_normalize()?;
+    let mut coord = origin.floor().as_ivec3();
+
+    let mut step = IVec3::ZERO;
+    // Distance along the ray to the next block boundary on each axis, and between boundaries
+    let mut next = Vec3::splat(f32::INFINITY);
+    let mut delta = Vec3::splat(f32::INFINITY);
+    for axis in 0..3 {
+        if direction[axis] > 0. {
+            step[axis] = 1;
+            next[axis] = (coord[axis] as f32 + 1. - origin[axis]) / direction[axis];
+        } else if direction[axis] < 0. {
+            step[axis] = -1;
+            next[axis] = (origin[axis] - coord[axis] as f32) / -direction[axis];
+        } else {
+            continue;
+        }
+        delta[axis] = 1. / direction[axis].abs();
+    }
+
+    loop {
+        let axis = if next.x <= next.y && next.x <= next.z {
+            0
+        } else if next.y <= next.z {
+            1
+        } else {
+            2
+        };
+        if next[axis] > max_distance {
+            return None;
+        }
+
+        coord[axis] += step[axis];
+        next[axis] += delta[axis];
+        let mut normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+
+        if world.is_solid(coord) && !hidden(coord) {
+            return Some(RayHit {
+                coord,
+                block: world.get(coord),
+                normal,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use macroquad::math::{ivec3, vec3};
+
+    use super::*;
+
+    #[test]
+    fn hits_the_face_facing_the_ray() {
+        let world = World::with_blocks(&[
+            (ivec3(0, 0, 0), "minecraft:stone"),
+            (ivec3(3, 2, -4), "minecraft:stone"),
+        ]);
+
+        let hit = raycast(&world, vec3(0.5, 5.5, 0.5), Vec3::NEG_Y, 100., |_| false).unwrap();
+        assert_eq!((hit.coord, hit.normal), (ivec3(0, 0, 0), IVec3::Y));
+        assert_eq!(world.palette[hit.block as usize].name, "minecraft:stone");
+
+        // Diagonally, across block boundaries on every axis
+        let origin = vec3(0.5, 0.5, 0.5);
+        let hit = raycast(&world, origin, vec3(3., 2., -4.6), 100., |_| false).unwrap();
+        assert_eq!(hit.coord, ivec3(3, 2, -4));
+        assert_eq!(hit.coord + hit.normal, ivec3(2, 2, -4));
+    }
+
+    #[test]
+    fn misses_and_hidden_blocks() {
+        let world = World::with_blocks(&[
+            (ivec3(0, 0, 0), "minecraft:stone"),
+            (ivec3(0, -2, 0), "minecraft:stone"),
+        ]);
+        let down = |max_distance, hidden: &dyn Fn(IVec3) -> bool| {
+            raycast(
+                &world,
+                vec3(0.5, 5.5, 0.5),
+                Vec3::NEG_Y,
+                max_distance,
+                hidden,
+            )
+            .map(|hit| hit.coord)
+        };
+
+        assert_eq!(down(4., &|_| false), None);
+        assert_eq!(down(100., &|coord| coord.y == 0), Some(ivec3(0, -2, 0)));
+        assert_eq!(down(100., &|_| true), None);
+        // The block the ray starts in does not count
+        let inside = raycast(&world, vec3(0.5, 0.5, 0.5), Vec3::X, 100., |_| false);
+        assert_eq!(inside, None);
+    }
+}
@@ -1,34 +1,113 @@
-use macroquad::{models::Vertex, prelude::*};
+use std::collections::{BTreeMap, HashMap};
+
+use macroquad::{miniquad::TextureWrap, models::Vertex, prelude::*};
+use protocol::PositionSource;
 
 use crate::{
-    objects::{Block, KeyboardEventHandler, VoxelCamera},
-    SCAN_WIDTH, SCAN_WIDTH_CUBED, SCREEN_HEIGHT, SCREEN_WIDTH,
+    autopilot::AutopilotStatus,
+    frustum::Frustum,
+    marker::TurtleMarker,
+    meshing::{self, Quad, FACES},
+    objects::{KeyboardEventHandler, VoxelCamera},
+    raycast::{raycast, RayHit},
+    rules::Rules,
+    sockets::Sockets,
+    textures::{self, BlockTextures},
+    world::{World, CHUNK_SIZE},
 };
 
 const INDICES: [u16; 6] = [0, 1, 2, 0, 2, 3];
+/// Macroquad's draw call limitation of 10000 vertices or 5000 indices.
+const MAX_VERTICES: usize = 10000;
+const MAX_INDICES: usize = 5000;
+/// Blocks further from the camera than this cannot be picked.
+const PICK_DISTANCE: f32 = 100.;
+
+/// The texture a face is drawn with, an index into `Renderer::block_textures`
+/// or `None` for the tinted stone texture, and the color it is multiplied with.
+type FaceLook = (Option<usize>, Color);
+
+/// How a block in the palette is drawn, after textures and rules.
+struct BlockLook {
+    /// By [`FACES`] index.
+    faces: [FaceLook; 6],
+    hidden: bool,
+    /// Blocks that can be seen through do not hide the faces behind them.
+    opaque: bool,
+}
+
+/// Meshes of one chunk as it was at `revision`, and the box they fit in.
+struct ChunkMesh {
+    revision: u64,
+    min: Vec3,
+    max: Vec3,
+    meshes: Vec<Mesh>,
+    /// Drawn after every opaque mesh, so what is behind them is there to blend with.
+    translucent: Vec<Mesh>,
+}
 
 #[derive(Default)]
 pub struct Renderer {
-    pub blocks: Vec<Block>,
+    pub world: World,
     pub objects_to_render: f32,
+    /// World height that slicing with `objects_to_render` is relative to.
+    pub slice_origin: f32,
+    /// Every turtle whose pose is known, keyed by client id.
+    pub turtles: BTreeMap<u64, TurtleMarker>,
+    /// Call [`Renderer::rules_changed`] after changing them.
+    pub rules: Rules,
+    /// Textures are uploaded on the first frame, when the graphics context exists.
+    stone_texture: Option<Texture2D>,
+    block_textures: BlockTextures,
+    uploaded_textures: Vec<Texture2D>,
+    /// By palette id.
+    block_looks: Vec<BlockLook>,
+    chunk_meshes: HashMap<IVec3, ChunkMesh>,
+    /// `objects_to_render` and `slice_origin` the chunk meshes were built with.
+    meshed_slice: (f32, f32),
 }
 
 impl Renderer {
     pub fn new() -> Self {
-        let blocks: Vec<Block> = vec![Default::default(); SCAN_WIDTH_CUBED as usize];
+        let world = World::new();
         let objects_to_render = 0.;
+        let slice_origin = 0.;
+        let turtles = BTreeMap::new();
 
         Renderer {
-            blocks,
+            world,
             objects_to_render,
+            slice_origin,
+            turtles,
+            rules: Rules::default(),
+            stone_texture: None,
+            block_textures: BlockTextures::default(),
+            uploaded_textures: vec![],
+            block_looks: vec![],
+            chunk_meshes: HashMap::new(),
+            meshed_slice: (objects_to_render, slice_origin),
         }
     }
 
-    pub async fn draw(&self, camera: &VoxelCamera, keyboard_events: &KeyboardEventHandler) {
+    /// Draws blocks with `textures` where they have one, rebuilding every chunk.
+    pub fn set_textures(&mut self, textures: BlockTextures) {
+        self.block_textures = textures;
+        self.uploaded_textures.clear();
+        self.rules_changed();
+    }
+
+    /// Rebuilds every chunk with the current `rules`.
+    pub fn rules_changed(&mut self) {
+        self.block_looks.clear();
+        self.chunk_meshes.clear();
+    }
+
+    pub async fn draw(&mut self, camera: &VoxelCamera, keyboard_events: &KeyboardEventHandler) {
         clear_background(LIGHTGRAY);
 
         draw_grid(20, 1., BLACK, GRAY);
-        self.mesh();
+        self.mesh(camera);
+        self.draw_turtles();
 
         self.draw_ui(camera, keyboard_events);
 
@@ -41,412 +120,338 @@ impl Renderer {
 
         draw_text(
             "+",
-            SCREEN_WIDTH as f32 / 2.,
-            SCREEN_HEIGHT as f32 / 2.,
+            screen_width() / 2.,
+            screen_height() / 2.,
             30.,
             DARKGRAY,
         );
         if KeyboardEventHandler::left_clicked() && keyboard_events.mouse_grabbed {
-            let block_name = self.ray_march(camera);
+            let block_name = self.pick(camera).map_or("", |hit| {
+                self.world.palette[hit.block as usize].name.as_str()
+            });
             if !block_name.is_empty() {
                 draw_rectangle(8., 8., 10. + block_name.len() as f32 * 16., 39., DARKGRAY);
             }
             draw_text(block_name, 20.0, 35.0, 35., WHITE);
         }
-    }
 
-    fn mesh(&self) {
-        let mut vertices: Vec<Vertex> = vec![];
-        let mut indices: Vec<u16> = vec![];
-        let mut indicies_index: u32 = 0;
+        if let Some(marker) = self.turtles.values().find(|marker| marker.active) {
+            let position = marker.pose.position;
+            let source = match marker.pose.source {
+                PositionSource::Gps => "GPS",
+                PositionSource::DeadReckoning => "dead reckoning",
+            };
+            let text = format!(
+                "{}: {} {} {} facing {:?} ({})",
+                marker.name, position.x, position.y, position.z, marker.pose.heading, source
+            );
 
-        let texture = Some(Texture2D::from_file_with_format(
-            include_bytes!("smooth_stone.png"),
-            None,
-        ));
-        texture.as_ref().unwrap().set_filter(FilterMode::Nearest);
+            draw_text(&text, 10., screen_height() - 14., 24., DARKGRAY);
+        }
+    }
 
-        for block in self.blocks.iter() {
-            if block.name == "minecraft:air" {
-                continue;
-            }
+    /// Moves the turtle markers towards the latest poses reported by the turtles.
+    pub fn update_turtles(&mut self, sockets: &Sockets) {
+        let delta = get_frame_time();
 
-            // Slicing
-            if ((block.coord.y >= self.objects_to_render
-                && self.objects_to_render.is_sign_positive())
-                || (block.coord.y <= self.objects_to_render.abs()
-                    && self.objects_to_render.is_sign_negative()))
-                && self.objects_to_render != 0.0
-            {
+        self.turtles
+            .retain(|client_id, _| sockets.turtles.contains_key(client_id));
+        for (client_id, turtle) in sockets.turtles.iter() {
+            let Some(pose) = turtle.pose else {
                 continue;
-            }
-
-            let mut block_vertices: Vec<Vertex> = vec![];
-            let mut block_indices: Vec<u16> = vec![];
-
-            for quad in 0..6 {
-                if !self.quad_is_visible(quad, block) {
-                    continue;
+            };
+
+            let marker = self
+                .turtles
+                .entry(*client_id)
+                .or_insert_with(|| TurtleMarker::new(turtle.name(), pose));
+            marker.name = turtle.name();
+            marker.pose = pose;
+            marker.active = sockets.active == Some(*client_id);
+            marker.path = match &turtle.autopilot {
+                Some(autopilot) if autopilot.status == AutopilotStatus::Running => {
+                    autopilot.waypoints(&pose)
                 }
+                _ => vec![],
+            };
+            marker.animate(delta);
+        }
+    }
 
-                block_vertices.append(&mut Self::get_quad_data(quad, block));
-                block_indices.append(&mut vec![
-                    INDICES[0] + indicies_index as u16,
-                    INDICES[1] + indicies_index as u16,
-                    INDICES[2] + indicies_index as u16,
-                    INDICES[3] + indicies_index as u16,
-                    INDICES[4] + indicies_index as u16,
-                    INDICES[5] + indicies_index as u16,
-                ]);
-
-                indicies_index += 4;
-            }
-
-            vertices.append(&mut block_vertices);
-            indices.append(&mut block_indices);
+    fn draw_turtles(&self) {
+        for marker in self.turtles.values() {
+            marker.draw();
+        }
+    }
 
-            // Macroquad's draw call limitation of 10000 vertices or 5000 indices
-            if vertices.len() > 9800 || indices.len() > 4800 {
-                draw_mesh(&Mesh {
-                    vertices: vertices.clone(),
-                    indices: indices.clone(),
-                    texture: texture.clone(),
-                });
+    /// Draws the chunks in view, first rebuilding those whose blocks changed
+    /// and those the slice moved through.
+    fn mesh(&mut self, camera: &VoxelCamera) {
+        let slice = (self.objects_to_render, self.slice_origin);
+        if slice != self.meshed_slice {
+            self.unmesh_slice_change(self.meshed_slice, slice);
+            self.meshed_slice = slice;
+        }
 
-                vertices = vec![];
-                indices = vec![];
-                indicies_index = 0;
+        let world = &self.world;
+        self.chunk_meshes
+            .retain(|chunk_coord, _| world.chunks.contains_key(chunk_coord));
+        let dirty: Vec<IVec3> = world
+            .chunks
+            .iter()
+            .filter(|(chunk_coord, chunk)| {
+                self.chunk_meshes
+                    .get(chunk_coord)
+                    .is_none_or(|mesh| mesh.revision != chunk.revision)
+            })
+            .map(|(chunk_coord, _)| *chunk_coord)
+            .collect();
+
+        if !dirty.is_empty() {
+            self.prepare_looks();
+            for chunk_coord in dirty {
+                let mesh = self.build_chunk_mesh(chunk_coord);
+                self.chunk_meshes.insert(chunk_coord, mesh);
             }
         }
 
-        draw_mesh(&Mesh {
-            vertices: vertices.clone(),
-            indices: indices.clone(),
-            texture: texture.clone(),
-        });
+        let frustum = Frustum::from_matrix(camera.camera().matrix());
+        let mut visible: Vec<&ChunkMesh> = self
+            .chunk_meshes
+            .values()
+            .filter(|chunk| frustum.contains_box(chunk.min, chunk.max))
+            .collect();
+        for mesh in visible.iter().flat_map(|chunk| &chunk.meshes) {
+            draw_mesh(mesh);
+        }
+        // Translucent faces still write depth, so the furthest go first or
+        // nearer ones would hide them instead of blending over them
+        let distance = |chunk: &ChunkMesh| {
+            camera
+                .position
+                .distance_squared((chunk.min + chunk.max) / 2.)
+        };
+        visible.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+        for mesh in visible.iter().flat_map(|chunk| &chunk.translucent) {
+            draw_mesh(mesh);
+        }
     }
 
-    fn ray_march(&self, camera: &VoxelCamera) -> &str {
-        let max_steps = 10000;
-        let max_distance = 100.;
-        let surface_distance = 0.01;
-
-        let ray_origin = camera.position;
-        let ray_direction = camera.direction;
-
-        let mut distance = 0.;
-        let mut block_name = "";
+    /// Uploads the textures if this is the first frame, and works out how
+    /// blocks added to the palette since the last call look.
+    fn prepare_looks(&mut self) {
+        if self.stone_texture.is_none() {
+            let stone = Texture2D::from_file_with_format(include_bytes!("smooth_stone.png"), None);
+            self.stone_texture = Some(repeating(stone));
+        }
+        if self.uploaded_textures.len() < self.block_textures.len() {
+            self.uploaded_textures = self
+                .block_textures
+                .images
+                .iter()
+                .map(|image| repeating(Texture2D::from_image(image)))
+                .collect();
+        }
 
-        for _ in 0..max_steps {
-            let position = ray_origin + ray_direction * distance;
-            let closest_block = self.get_closest_block(position);
+        for block in &self.world.palette[self.block_looks.len()..] {
+            let rule = self.rules.find(&block.name);
+            let opacity = rule
+                .and_then(|rule| rule.opacity)
+                .unwrap_or(1.)
+                .clamp(0., 1.);
+
+            let faces = FACES.map(|normal| {
+                let (texture, color) = match self.block_textures.face_texture(&block.name, normal) {
+                    Some(texture) => (Some(texture), textures::tint(&block.name, normal)),
+                    None => (None, block.color),
+                };
+                let color = rule.and_then(|rule| rule.color).unwrap_or(color);
+                (
+                    texture,
+                    Color {
+                        a: opacity,
+                        ..color
+                    },
+                )
+            });
+            self.block_looks.push(BlockLook {
+                faces,
+                hidden: rule.is_some_and(|rule| rule.hidden),
+                opaque: opacity >= 1.,
+            });
+        }
+    }
 
-            distance += closest_block.0;
-            block_name = closest_block.1;
+    fn build_chunk_mesh(&self, chunk_coord: IVec3) -> ChunkMesh {
+        let mut builders: HashMap<(Option<usize>, bool), MeshBuilder> = HashMap::new();
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
 
-            if closest_block.0 < surface_distance {
-                return block_name;
+        let quads = meshing::chunk_quads(
+            &self.world,
+            chunk_coord,
+            |coord, id| self.block_looks[id as usize].hidden || self.is_sliced(coord),
+            |id| self.block_looks[id as usize].opaque,
+        );
+        for quad in quads {
+            let face = FACES
+                .iter()
+                .position(|normal| *normal == quad.normal)
+                .unwrap();
+            let look = &self.block_looks[quad.block as usize];
+            let (texture, color) = look.faces[face];
+
+            let builder = builders.entry((texture, look.opaque)).or_insert_with(|| {
+                let texture = match texture {
+                    Some(index) => &self.uploaded_textures[index],
+                    None => self.stone_texture.as_ref().unwrap(),
+                };
+                MeshBuilder::new(texture.clone())
+            });
+            for (position, _) in quad.corners() {
+                min = min.min(position);
+                max = max.max(position);
             }
-            if distance > max_distance {
-                return "";
+            builder.push(&quad, color);
+        }
+
+        let mut meshes = vec![];
+        let mut translucent = vec![];
+        for ((_, opaque), builder) in builders {
+            if opaque {
+                meshes.extend(builder.finish());
+            } else {
+                translucent.extend(builder.finish());
             }
         }
+        ChunkMesh {
+            revision: self.world.chunks[&chunk_coord].revision,
+            min,
+            max,
+            meshes,
+            translucent,
+        }
+    }
+
+    /// The block under the crosshair, skipping blocks that are sliced away or
+    /// hidden by a rule.
+    pub fn pick(&self, camera: &VoxelCamera) -> Option<RayHit> {
+        raycast(
+            &self.world,
+            camera.position,
+            camera.direction,
+            PICK_DISTANCE,
+            |coord| {
+                let id = self.world.get(coord) as usize;
+                // Blocks the looks are not worked out for yet, until the next frame
+                let hidden = match self.block_looks.get(id) {
+                    Some(look) => look.hidden,
+                    None => self.rules.hides(&self.world.palette[id].name),
+                };
+                self.is_sliced(coord) || hidden
+            },
+        )
+    }
+
+    /// Drops the meshes of chunks with blocks that moving the slice from
+    /// `old` to `new`, as `(objects_to_render, slice_origin)`, hides or shows.
+    fn unmesh_slice_change(&mut self, old: (f32, f32), new: (f32, f32)) {
+        let (old_layers, new_layers) = (old.0, new.0);
+        // The origin does not matter while slicing is off
+        if old_layers == 0. && new_layers == 0. {
+            return;
+        }
+        if old_layers == 0.
+            || new_layers == 0.
+            || old_layers.is_sign_positive() != new_layers.is_sign_positive()
+        {
+            self.chunk_meshes.clear();
+            return;
+        }
 
-        block_name
+        // The same side is hidden, so only the blocks between the two planes
+        // change, along with the faces of the blocks next to them
+        let plane = |(layers, origin): (f32, f32)| origin + layers.abs();
+        let low = plane(old).min(plane(new)).floor() as i32 - 1;
+        let high = plane(old).max(plane(new)).ceil() as i32 + 1;
+        self.chunk_meshes.retain(|chunk_coord, _| {
+            let bottom = chunk_coord.y * CHUNK_SIZE;
+            bottom + CHUNK_SIZE <= low || bottom > high
+        });
     }
 
-    fn get_closest_block(&self, point: Vec3) -> (f32, &str) {
-        let mut shortest_distance = f32::INFINITY;
-        let mut block_name = "";
+    /// Whether slicing with `objects_to_render` hides blocks at this height.
+    fn is_sliced(&self, coord: IVec3) -> bool {
+        let y = coord.y as f32 - self.slice_origin;
 
-        for block in self.blocks.iter() {
-            if block.name == "minecraft:air" {
-                continue;
-            }
+        ((y >= self.objects_to_render && self.objects_to_render.is_sign_positive())
+            || (y <= self.objects_to_render.abs() && self.objects_to_render.is_sign_negative()))
+            && self.objects_to_render != 0.0
+    }
+}
 
-            // Slicing
-            if ((block.coord.y >= self.objects_to_render
-                && self.objects_to_render.is_sign_positive())
-                || (block.coord.y <= self.objects_to_render.abs()
-                    && self.objects_to_render.is_sign_negative()))
-                && self.objects_to_render != 0.0
-            {
-                continue;
-            }
+/// Sets `texture` up so merged faces repeat it once per block.
+fn repeating(texture: Texture2D) -> Texture2D {
+    texture.set_filter(FilterMode::Nearest);
+    let gl = unsafe { get_internal_gl() };
+    gl.quad_context.texture_set_wrap(
+        texture.raw_miniquad_id(),
+        TextureWrap::Repeat,
+        TextureWrap::Repeat,
+    );
+
+    texture
+}
 
-            let center = block.coord + 0.5;
+/// Quads sharing a texture, split into meshes small enough for one draw call.
+struct MeshBuilder {
+    texture: Texture2D,
+    meshes: Vec<Mesh>,
+    vertices: Vec<Vertex>,
+    indices: Vec<u16>,
+}
 
-            let delta = vec3(
-                (point.x - center.x).abs() - 0.5,
-                (point.y - center.y).abs() - 0.5,
-                (point.z - center.z).abs() - 0.5,
-            );
-            let sdf_value = delta.x.max(delta.y).max(delta.z).max(0.);
+impl MeshBuilder {
+    fn new(texture: Texture2D) -> Self {
+        Self {
+            texture,
+            meshes: vec![],
+            vertices: vec![],
+            indices: vec![],
+        }
+    }
 
-            if sdf_value < shortest_distance {
-                shortest_distance = sdf_value;
-                block_name = &block.name;
-            }
+    fn push(&mut self, quad: &Quad, color: Color) {
+        if self.vertices.len() + 4 > MAX_VERTICES
+            || self.indices.len() + INDICES.len() > MAX_INDICES
+        {
+            self.flush();
         }
 
-        (shortest_distance, block_name)
+        let first = self.vertices.len() as u16;
+        for (position, uv) in quad.corners() {
+            self.vertices.push(Vertex {
+                position,
+                uv,
+                color,
+            });
+        }
+        self.indices.extend(INDICES.map(|index| first + index));
     }
 
-    fn get_quad_data(quad: usize, block: &Block) -> Vec<Vertex> {
-        let Block {
-            name: _,
-            coord:
-                Vec3 {
-                    x: min_x,
-                    y: min_y,
-                    z: min_z,
-                },
-            color,
-        } = block.to_owned();
-
-        let max_x = min_x + 1.;
-        let max_y = min_y + 1.;
-        let max_z = min_z + 1.;
-
-        match quad {
-            // Front face
-            0 => vec![
-                Vertex {
-                    position: vec3(min_x, min_y, max_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, min_y, max_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, max_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, max_y, max_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-            ],
-            // Back face
-            1 => vec![
-                Vertex {
-                    position: vec3(min_x, min_y, min_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, min_y, min_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, min_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, max_y, min_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-            ],
-            // Top face
-            2 => vec![
-                Vertex {
-                    position: vec3(min_x, max_y, min_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, max_y, max_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, max_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, min_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-            ],
-            // Bottom face
-            3 => vec![
-                Vertex {
-                    position: vec3(min_x, min_y, min_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, min_y, max_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, min_y, max_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, min_y, min_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-            ],
-            // Right face
-            4 => vec![
-                Vertex {
-                    position: vec3(max_x, min_y, min_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, min_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, max_y, max_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(max_x, min_y, max_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-            ],
-            // Left face
-            5 => vec![
-                Vertex {
-                    position: vec3(min_x, min_y, min_z),
-                    uv: vec2(1., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, max_y, min_z),
-                    uv: vec2(0., 0.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, max_y, max_z),
-                    uv: vec2(0., 1.),
-                    color,
-                },
-                Vertex {
-                    position: vec3(min_x, min_y, max_z),
-                    uv: vec2(1., 1.),
-                    color,
-                },
-            ],
-            _ => panic!("Quad indexing out of range"),
-        }
+    fn flush(&mut self) {
+        self.meshes.push(Mesh {
+            vertices: std::mem::take(&mut self.vertices),
+            indices: std::mem::take(&mut self.indices),
+            texture: Some(self.texture.clone()),
+        });
     }
 
-    fn quad_is_visible(&self, quad: usize, block: &Block) -> bool {
-        let coord = block.coord;
-
-        match quad {
-            0 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x, coord.y, coord.z + 1.)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.z == SCAN_WIDTH as f32 - 1. {
-                    return true;
-                }
-            }
-            1 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x, coord.y, coord.z - 1.0)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.z == 0. {
-                    return true;
-                }
-            }
-            2 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x, coord.y + 1., coord.z)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.y == SCAN_WIDTH as f32 - 1. {
-                    return true;
-                }
-                // Slicing
-                if coord.y + 1. == self.objects_to_render
-                    && self.objects_to_render.is_sign_positive()
-                {
-                    return true;
-                }
-            }
-            3 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x, coord.y - 1., coord.z)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.y == 0. {
-                    return true;
-                }
-                // Slicing
-                if coord.y - 1. == self.objects_to_render.abs()
-                    && self.objects_to_render.is_sign_negative()
-                {
-                    return true;
-                }
-            }
-            4 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x + 1., coord.y, coord.z)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.x == SCAN_WIDTH as f32 - 1. {
-                    return true;
-                }
-            }
-            5 => {
-                if let Some(adjacent_block) =
-                    self.blocks
-                        .get(Block::linearize(Vec3::new(coord.x - 1., coord.y, coord.z)) as usize)
-                {
-                    if adjacent_block.name == "minecraft:air" {
-                        return true;
-                    }
-                }
-                if coord.x == 0. {
-                    return true;
-                }
-            }
-            _ => unreachable!("Quad indexing out of range"),
+    fn finish(mut self) -> Vec<Mesh> {
+        if !self.indices.is_empty() {
+            self.flush();
         }
-        false
+
+        self.meshes
     }
 }
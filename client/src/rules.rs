@@ -0,0 +1,178 @@
+//! Colors and visibility per block name, from a TOML file of rules. The first
+//! enabled rule whose pattern matches a block decides how it is drawn.
+//!
+//! ```toml
+//! [[rule]]
+//! blocks = "*:*_ore"
+//! color = "#ffd700"
+//!
+//! [[rule]]
+//! blocks = "minecraft:stone"
+//! opacity = 0.2
+//!
+//! [[rule]]
+//! blocks = "minecraft:dirt"
+//! hidden = true
+//! enabled = false
+//! ```
+
+use std::path::Path;
+
+use macroquad::color::Color;
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::config::ConfigError;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Block names this rule applies to, `*` matching any run of characters
+    /// and `?` any single one.
+    pub blocks: String,
+    /// Replaces the block's own color, textures are multiplied with it.
+    #[serde(default, deserialize_with = "hex_color")]
+    pub color: Option<Color>,
+    /// From 0 for invisible to 1 for solid.
+    #[serde(default)]
+    pub opacity: Option<f32>,
+    /// Leaves the blocks out of the world view and picking.
+    #[serde(default)]
+    pub hidden: bool,
+    /// Disabled rules are skipped, they can be turned on from the rules window.
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn hex_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let hex = text.strip_prefix('#').unwrap_or(&text);
+
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 => Ok(Some(Color::from_rgba(
+            (rgb >> 16) as u8,
+            (rgb >> 8) as u8,
+            rgb as u8,
+            255,
+        ))),
+        _ => Err(D::Error::custom(format!(
+            "{} is not a color like \"#ffd700\"",
+            text
+        ))),
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.to_owned(), err))?;
+
+        toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    /// The rule deciding how `block` is drawn, if any.
+    pub fn find(&self, block: &str) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.enabled && glob_matches(&rule.blocks, block))
+    }
+
+    pub fn hides(&self, block: &str) -> bool {
+        self.find(block).is_some_and(|rule| rule.hidden)
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+/// and `?` any single one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it has taken so far
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*:*_ore", "minecraft:diamond_ore"));
+        assert!(glob_matches("*:*_ore", "create:zinc_ore"));
+        assert!(!glob_matches(
+            "*:*_ore",
+            "minecraft:deepslate_diamond_ore_block"
+        ));
+        assert!(glob_matches("minecraft:stone", "minecraft:stone"));
+        assert!(!glob_matches("minecraft:stone", "minecraft:stone_bricks"));
+        assert!(glob_matches("minecraft:?_*", "minecraft:a_b"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("?", ""));
+    }
+
+    #[test]
+    fn the_first_enabled_rule_wins() {
+        let rules: Rules = toml::from_str(
+            r##"
+            [[rule]]
+            blocks = "minecraft:dirt"
+            hidden = true
+            enabled = false
+
+            [[rule]]
+            blocks = "*:*_ore"
+            color = "#ffd700"
+
+            [[rule]]
+            blocks = "minecraft:*"
+            opacity = 0.2
+            hidden = true
+            "##,
+        )
+        .unwrap();
+
+        let ore = rules.find("minecraft:gold_ore").unwrap();
+        assert_eq!(ore.color, Some(Color::from_rgba(255, 215, 0, 255)));
+        assert!(!rules.hides("minecraft:iron_ore"));
+        assert_eq!(rules.find("minecraft:dirt").unwrap().opacity, Some(0.2));
+        assert!(rules.hides("minecraft:dirt"));
+        assert!(rules.find("create:andesite_casing").is_none());
+
+        let bad = toml::from_str::<Rules>("[[rule]]\nblocks = \"*\"\ncolor = \"gold\"");
+        assert!(bad.unwrap_err().to_string().contains("gold"));
+    }
+}
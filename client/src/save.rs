@@ -0,0 +1,248 @@
+//! On-disk world format, all integers little endian:
+//!
+//! ```text
+//! magic "CCWS", format version u16
+//! palette:  u32 count, then per entry u16 length + UTF-8 block name
+//! chunks:   u32 count, then per chunk
+//!           i32 x, y, z chunk coordinate
+//!           u32 run count, then per run u16 length + u16 palette index
+//!           u32 run count, then per run u16 length + u64 last seen
+//! ```
+//!
+//! Like `serialize` in cc-script.lua, blocks are stored as indices into a
+//! palette of names. Chunks are mostly long stretches of air or stone, so both
+//! the indices and timestamps are run-length encoded.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use macroquad::math::ivec3;
+
+use crate::world::{BlockId, Chunk, World, CHUNK_VOLUME};
+
+const MAGIC: &[u8; 4] = b"CCWS";
+const FORMAT_VERSION: u16 = 1;
+
+pub fn save_world(world: &World, path: &Path) -> io::Result<()> {
+    // Write next to the old save and swap it in, so a crash never leaves half a world
+    let temporary_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary_path)?);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+    writer.write_all(&(world.palette.len() as u32).to_le_bytes())?;
+    for block_type in world.palette.iter() {
+        writer.write_all(&(block_type.name.len() as u16).to_le_bytes())?;
+        writer.write_all(block_type.name.as_bytes())?;
+    }
+
+    writer.write_all(&(world.chunks.len() as u32).to_le_bytes())?;
+    for (coord, chunk) in world.chunks.iter() {
+        for component in coord.to_array() {
+            writer.write_all(&component.to_le_bytes())?;
+        }
+
+        write_runs(&mut writer, &chunk.blocks, |writer, id| {
+            writer.write_all(&id.to_le_bytes())
+        })?;
+        write_runs(&mut writer, &chunk.last_seen, |writer, seen| {
+            writer.write_all(&seen.to_le_bytes())
+        })?;
+    }
+
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(temporary_path, path)
+}
+
+pub fn load_world(path: &Path) -> io::Result<World> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a world file"));
+    }
+    let version = read_u16(&mut reader)?;
+    if version != FORMAT_VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported world format version {}",
+            version
+        )));
+    }
+
+    // Palette ids in the file are remapped, the world assigns its own
+    let mut world = World::new();
+    let mut ids: Vec<BlockId> = vec![];
+    for _ in 0..read_u32(&mut reader)? {
+        let mut name = vec![0; read_u16(&mut reader)? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid_data("block name is not UTF-8"))?;
+
+        ids.push(world.block_id(&name));
+    }
+
+    for _ in 0..read_u32(&mut reader)? {
+        let coord = ivec3(
+            read_i32(&mut reader)?,
+            read_i32(&mut reader)?,
+            read_i32(&mut reader)?,
+        );
+
+        let blocks = read_runs(&mut reader, |reader| {
+            let index = read_u16(reader)? as usize;
+            ids.get(index)
+                .copied()
+                .ok_or_else(|| invalid_data("palette index out of range"))
+        })?;
+        let last_seen = read_runs(&mut reader, read_u64)?;
+
+        world.chunks.insert(
+            coord,
+            Chunk {
+                blocks,
+                last_seen,
+                revision: 0,
+            },
+        );
+    }
+
+    Ok(world)
+}
+
+fn write_runs<W: Write, T: Copy + PartialEq>(
+    writer: &mut W,
+    values: &[T],
+    write_value: impl Fn(&mut W, T) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut runs: Vec<(u16, T)> = vec![];
+    for value in values.iter() {
+        match runs.last_mut() {
+            Some((length, run_value)) if run_value == value => *length += 1,
+            _ => runs.push((1, *value)),
+        }
+    }
+
+    writer.write_all(&(runs.len() as u32).to_le_bytes())?;
+    for (length, value) in runs {
+        writer.write_all(&length.to_le_bytes())?;
+        write_value(writer, value)?;
+    }
+
+    Ok(())
+}
+
+fn read_runs<R: Read, T: Copy>(
+    reader: &mut R,
+    mut read_value: impl FnMut(&mut R) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let mut values = Vec::with_capacity(CHUNK_VOLUME);
+
+    for _ in 0..read_u32(reader)? {
+        let length = read_u16(reader)? as usize;
+        let value = read_value(reader)?;
+        // Check as we go, a corrupt run count could otherwise fill memory
+        if values.len() + length > CHUNK_VOLUME {
+            return Err(invalid_data("chunk has too many blocks"));
+        }
+        values.extend(std::iter::repeat_n(value, length));
+    }
+
+    if values.len() != CHUNK_VOLUME {
+        return Err(invalid_data("chunk has the wrong number of blocks"));
+    }
+
+    Ok(values)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::math::IVec3;
+
+    #[test]
+    fn worlds_survive_a_round_trip() {
+        let mut world = World::new();
+        let names = vec![
+            "minecraft:stone".to_string(),
+            "minecraft:iron_ore".to_string(),
+        ];
+        let mut blocks = vec![1; 27];
+        blocks[13] = 0;
+        blocks[0] = 2;
+        world.merge_scan(ivec3(-8, 70, 15), 1, &names, &blocks, 1234);
+
+        let path = std::env::temp_dir().join(format!(
+            "cc-websockets-round-trip-{}.world",
+            std::process::id()
+        ));
+        save_world(&world, &path).unwrap();
+        let loaded = load_world(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.chunks.len(), world.chunks.len());
+        for (coord, block_type) in world.solid_blocks() {
+            assert_eq!(loaded.solid_block(coord), Some(block_type));
+            assert_eq!(loaded.last_seen(coord), Some(1234));
+        }
+        assert!(!loaded.is_solid(ivec3(-8, 70, 15)));
+        assert_eq!(loaded.last_seen(IVec3::ZERO), None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path =
+            std::env::temp_dir().join(format!("cc-websockets-not-a-{}.world", std::process::id()));
+        std::fs::write(&path, b"2[\"minecraft:stone\"][0,1]").unwrap();
+
+        let err = load_world(&path).err().unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_runs_past_the_end_of_a_chunk() {
+        // Claims four billion runs, each longer than a whole chunk
+        let mut file = vec![];
+        file.extend(u32::MAX.to_le_bytes());
+        for _ in 0..2 {
+            file.extend(u16::MAX.to_le_bytes());
+            file.extend(0u16.to_le_bytes());
+        }
+
+        let err = read_runs(&mut &file[..], read_u16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "chunk has too many blocks");
+    }
+}
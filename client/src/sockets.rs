@@ -1,110 +1,315 @@
-use macroquad::color::Color;
-use simple_websockets::{Event, EventHub, Message, Responder};
+use std::collections::BTreeMap;
 
-use crate::objects::Block;
+use macroquad::math::IVec3;
+use protocol::CommandId;
+use simple_websockets::{Event, EventHub, Message};
+
+use crate::{config::Config, turtle::Turtle, world::World};
 
 pub struct Sockets {
     pub event_hub: EventHub,
-    pub client: Option<Responder>,
+    /// Every connected turtle, keyed by simple_websockets client id.
+    pub turtles: BTreeMap<u64, Turtle>,
+    /// The turtle that commands are sent to and whose scans are rendered.
+    pub active: Option<u64>,
 }
 
 impl Sockets {
+    /// Listens on the default port from [`Config`].
     pub fn new() -> Sockets {
-        let event_hub = simple_websockets::launch(1234).expect("failed to listen on port 1234");
-        let client = None;
+        Self::listen(Config::default().port)
+    }
 
-        Self { event_hub, client }
+    pub fn listen(port: u16) -> Sockets {
+        let event_hub = simple_websockets::launch(port)
+            .unwrap_or_else(|_| panic!("failed to listen on port {}", port));
+        let turtles = BTreeMap::new();
+        let active = None;
+
+        Self {
+            event_hub,
+            turtles,
+            active,
+        }
     }
 
-    pub fn process(&mut self, blocks: &mut [Block]) {
-        if let Some(event) = self.event_hub.next_event() {
+    /// Handles everything turtles sent since the last call, `now` being
+    /// seconds on the same clock commands time out by.
+    pub fn process(&mut self, world: &mut World, now: f64) {
+        while let Some(event) = self.event_hub.next_event() {
             match event {
-                Event::Connect(_, responder) => {
-                    log::info!("Turtle connected.");
+                Event::Connect(client_id, responder) => {
+                    log::info!("Turtle connected as client {}.", client_id);
 
-                    self.client = Some(responder);
+                    self.turtles
+                        .insert(client_id, Turtle::new(client_id, responder));
+                    if self.active.is_none() {
+                        self.select(client_id);
+                    }
                 }
-                Event::Disconnect(_) => {
-                    log::info!("Turtle disconnected.");
+                Event::Disconnect(client_id) => {
+                    if let Some(turtle) = self.turtles.remove(&client_id) {
+                        log::info!("{} disconnected.", turtle.name());
+                    }
 
-                    self.client = None;
+                    if self.active == Some(client_id) {
+                        self.active = None;
+                        if let Some(&next) = self.turtles.keys().next() {
+                            self.select(next);
+                        }
+                    }
                 }
-                Event::Message(_, msg_frame) => {
-                    if let Message::Text(msg) = msg_frame.clone() {
-                        Self::message_event(&msg, blocks);
+                Event::Message(client_id, Message::Text(msg)) => {
+                    if let Some(turtle) = self.turtles.get_mut(&client_id) {
+                        turtle.message_event(&msg, world);
                     }
                 }
+                Event::Message(client_id, Message::Binary(_)) => {
+                    log::warn!("Ignoring binary message from client {}", client_id);
+                }
             }
-        };
+        }
+
+        for turtle in self.turtles.values_mut() {
+            turtle.process(now, world);
+        }
+    }
+
+    /// Makes `client_id` the turtle that receives commands.
+    pub fn select(&mut self, client_id: u64) {
+        if let Some(turtle) = self.turtles.get(&client_id) {
+            log::info!("Controlling {}.", turtle.name());
+
+            self.active = Some(client_id);
+        }
     }
 
-    pub fn send_message(&self, text: String) {
-        if let Some(responder) = &self.client {
-            responder.send(Message::Text(text));
+    pub fn active_turtle(&self) -> Option<&Turtle> {
+        self.turtles.get(&self.active?)
+    }
+
+    pub fn active_turtle_mut(&mut self) -> Option<&mut Turtle> {
+        self.turtles.get_mut(&self.active?)
+    }
+
+    /// Sends the active turtle to `goal` along a path through the known world.
+    pub fn go_to(&mut self, goal: IVec3, allow_digging: bool) {
+        match self.active_turtle_mut() {
+            Some(turtle) => turtle.go_to(goal, allow_digging),
+            None => log::error!("Cannot go to {}, no turtle connected!", goal),
+        }
+    }
+
+    /// Queues Lua code for the active turtle, returning the id its reply will carry.
+    pub fn send_message(&mut self, text: String) -> Option<CommandId> {
+        if let Some(turtle) = self.active_turtle_mut() {
+            Some(turtle.commands.issue(text))
         } else {
             log::error!("Cannot send message, no turtle connected!");
+
+            None
         }
     }
+}
+
+impl Default for Sockets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
 
-    fn message_event(msg: &str, blocks: &mut [Block]) {
-        // Message protocol: "[Message type integer][Data]" e.g. '0minecraft:stone'
-        // '0' -> Command executed successfully
-        // '1[Error message]' -> Commaand failed
-        // 2[Comma separated blocks][Block index] -> Geo Scanner data
-
-        if msg.starts_with('0') {
-            log::info!("Command executed successfully");
-        } else if let Some(inner_msg) = msg.strip_prefix('1') {
-            log::error!("Turtle does not understand the command! {}", inner_msg);
-        } else if msg.starts_with('2') {
-            let names = msg
-                .split("][")
-                .next()
+    use fake_turtle::{FakeTurtle, FakeWorld};
+    use macroquad::math::ivec3;
+    use protocol::TurtleMessage;
+
+    use super::*;
+    use crate::{autopilot::AutopilotStatus, commands::CommandState};
+
+    fn connect_fake_turtle(port: u16, turtle: FakeTurtle) -> Arc<Mutex<FakeTurtle>> {
+        let turtle = Arc::new(Mutex::new(turtle));
+        let shared = turtle.clone();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let url = format!("ws://127.0.0.1:{}", port);
+            if let Err(err) =
+                runtime.block_on(fake_turtle::run(&url, shared, Duration::from_millis(250)))
+            {
+                log::error!("Fake turtle disconnected: {}", err);
+            }
+        });
+
+        turtle
+    }
+
+    /// Processes events until `done` holds, failing the test after a few seconds.
+    fn process_until(
+        sockets: &mut Sockets,
+        world: &mut World,
+        done: impl Fn(&Sockets, &World) -> bool,
+    ) {
+        let start = Instant::now();
+        while !done(sockets, world) {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+
+            sockets.process(world, start.elapsed().as_secs_f64());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn fake_turtle_connects_and_scans() {
+        let mut sockets = Sockets::listen(41871);
+        let mut world = World::new();
+
+        let mut turtle = FakeTurtle::new(FakeWorld::flat(64), (5, 64, -3));
+        turtle.label = Some("Fake".to_owned());
+        turtle.scan_radius = 3;
+        let turtle = connect_fake_turtle(41871, turtle);
+
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            sockets
+                .active_turtle()
+                .is_some_and(|turtle| turtle.last_scan.is_some())
+        });
+        let active = sockets.active_turtle().unwrap();
+        assert_eq!(active.name(), "Fake");
+        assert_eq!(active.position(), ivec3(5, 64, -3));
+        // The inventory comes before the scan
+        assert_eq!(
+            active.inventory.as_ref().unwrap().fuel_text(),
+            "Fuel: unlimited"
+        );
+        assert_eq!(
+            world.solid_block(ivec3(5, 63, -3)).unwrap().name,
+            "minecraft:grass_block"
+        );
+        // The scan reaches three blocks down and no further
+        assert!(world.is_solid(ivec3(5, 61, -3)));
+        assert_eq!(world.get(ivec3(5, 60, -3)), crate::world::UNKNOWN);
+
+        let id = sockets
+            .send_message("return turtle.forward()".to_owned())
+            .unwrap();
+        // The pose is sent before the result, wait for both
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            let active = sockets.active_turtle().unwrap();
+            active.position() == ivec3(5, 64, -4)
+                && active.commands.get(id).unwrap().state.is_finished()
+        });
+        assert_eq!(
+            sockets
+                .active_turtle()
                 .unwrap()
-                .trim_start_matches("2[")
-                .trim_end_matches(']')
-                .split(',')
-                .map(|s| s.trim_matches('"').to_string())
-                .collect::<Vec<String>>();
-            let coords_linearized = msg
-                .split("][")
-                .nth(1)
+                .commands
+                .get(id)
                 .unwrap()
-                .trim_start_matches('[')
-                .trim_end_matches(']')
-                .split(',')
-                .map(|s| s.parse::<u16>().unwrap())
-                .collect::<Vec<u16>>();
-
-            assert!(
-                blocks.len() == coords_linearized.len(),
-                "turtle and client have different scan radiuses"
-            );
-
-            for (block_index, name_index) in coords_linearized.iter().enumerate() {
-                let block = &mut blocks[block_index];
-
-                block.coord = Block::delinearize(block_index as u16);
-                if *name_index > 0 {
-                    block.name = names[*name_index as usize - 1].to_owned();
-                    let hash: [u8; 16] = md5::compute(block.name.as_bytes()).into();
-
-                    block.color = Color::new(
-                        hash[0] as f32 / 255.,
-                        hash[1] as f32 / 255.,
-                        hash[2] as f32 / 255.,
-                        1.0,
-                    );
-                } else {
-                    block.name = "minecraft:air".to_string();
-                }
+                .state,
+            CommandState::Succeeded(vec![true.into()])
+        );
+        assert_eq!(turtle.lock().unwrap().position, (5, 64, -4));
+    }
+
+    #[test]
+    fn autopilot_drives_a_fake_turtle_around_a_wall() {
+        let mut sockets = Sockets::listen(41872);
+        let mut world = World::new();
+
+        let mut fake_world = FakeWorld::flat(0);
+        for x in -3..=3 {
+            for y in 0..4 {
+                fake_world.set((x, y, -2), Some("minecraft:stone"));
             }
         }
+        let turtle = FakeTurtle::new(fake_world, (0, 0, 0));
+        let turtle = connect_fake_turtle(41872, turtle);
+
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            sockets
+                .active_turtle()
+                .is_some_and(|turtle| turtle.last_scan.is_some())
+        });
+
+        sockets.go_to(ivec3(0, 0, -4), false);
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            let autopilot = sockets.active_turtle().unwrap().autopilot.as_ref();
+            autopilot.is_some_and(|autopilot| autopilot.status != AutopilotStatus::Running)
+        });
+
+        let active = sockets.active_turtle().unwrap();
+        assert_eq!(
+            active.autopilot.as_ref().unwrap().status,
+            AutopilotStatus::Arrived
+        );
+        assert_eq!(turtle.lock().unwrap().position, (0, 0, -4));
+        assert!(world.is_solid(ivec3(0, 0, -2)));
     }
-}
 
-impl Default for Sockets {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn commands_on_the_turtle_queue_do_not_time_out() {
+        let mut sockets = Sockets::listen(41873);
+        let mut world = World::new();
+
+        // fake-turtle runs commands as they arrive, so this turtle is scripted by hand
+        let (mut turtle, _) = tungstenite::connect("ws://127.0.0.1:41873").unwrap();
+        let mut send = |message: TurtleMessage| {
+            turtle
+                .send(tungstenite::Message::Text(message.encode()))
+                .unwrap()
+        };
+        send(TurtleMessage::Heartbeat);
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            sockets.active_turtle().is_some()
+        });
+
+        let first = sockets
+            .send_message("return turtle.dig()".to_owned())
+            .unwrap();
+        let second = sockets
+            .send_message("return turtle.forward()".to_owned())
+            .unwrap();
+        sockets.process(&mut world, 0.);
+        send(TurtleMessage::Queue {
+            running: Some(first),
+            queued: vec![second],
+        });
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            let commands = &sockets.active_turtle().unwrap().commands;
+            commands.get(first).unwrap().state == CommandState::Running
+        });
+
+        // Long after the timeout, both are still known to be on the turtle
+        sockets.process(&mut world, 1000.);
+        let commands = &sockets.active_turtle().unwrap().commands;
+        assert_eq!(commands.get(second).unwrap().state, CommandState::Pending);
+
+        send(TurtleMessage::CommandResult {
+            id: Some(first),
+            values: vec![true.into()],
+        });
+        send(TurtleMessage::Queue {
+            running: Some(second),
+            queued: vec![],
+        });
+        process_until(&mut sockets, &mut world, |sockets, _| {
+            let commands = &sockets.active_turtle().unwrap().commands;
+            commands.get(second).unwrap().state == CommandState::Running
+        });
+        let commands = &sockets.active_turtle().unwrap().commands;
+        assert_eq!(
+            commands.get(first).unwrap().state,
+            CommandState::Succeeded(vec![true.into()])
+        );
     }
 }
@@ -0,0 +1,376 @@
+//! Block textures from a directory of Minecraft-style PNGs or a resource-pack
+//! zip. Textures are looked up by block name, per face:
+//! `minecraft:oak_log` shows `oak_log_top` on top and bottom and `oak_log`
+//! on its sides.
+//!
+//! The textures are not packed into an atlas. Greedy meshing stretches one
+//! quad over many blocks, and the texture has to repeat across it. That needs
+//! wrap-around sampling, which an atlas tile cannot give without a custom
+//! shader. Each texture is uploaded on its own instead, and chunk meshes are
+//! split per texture, so draw calls grow with the number of textures in view.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek},
+    path::Path,
+};
+
+use macroquad::{
+    color::Color,
+    math::{IVec3, Rect},
+    prelude::ImageFormat,
+    texture::Image,
+};
+use zip::{result::ZipError, ZipArchive};
+
+/// Wider textures are skipped before decoding, since a PNG of a few bytes can
+/// claim any size. Packs go up to 512x512 per block.
+const MAX_TEXTURE_SIZE: u32 = 512;
+/// Animated textures stack their frames vertically.
+const MAX_TEXTURE_HEIGHT: u32 = MAX_TEXTURE_SIZE * 32;
+
+/// Blocks whose bottom is not named after the block itself.
+const BOTTOM_TEXTURES: [(&str, &str); 4] = [
+    ("minecraft:grass_block", "minecraft:dirt"),
+    ("minecraft:podzol", "minecraft:dirt"),
+    ("minecraft:mycelium", "minecraft:dirt"),
+    ("minecraft:dirt_path", "minecraft:dirt"),
+];
+/// Minecraft colors these gray textures by biome, plains colors are used here.
+const GRASS: Color = Color::new(0.57, 0.74, 0.35, 1.);
+const FOLIAGE: Color = Color::new(0.47, 0.67, 0.18, 1.);
+const WATER: Color = Color::new(0.25, 0.46, 0.89, 1.);
+
+#[derive(Default)]
+pub struct BlockTextures {
+    /// Square images, animated textures are cut down to their first frame.
+    pub images: Vec<Image>,
+    /// Index into `images` by texture name, e.g. `minecraft:oak_log_top`.
+    names: HashMap<String, usize>,
+}
+
+impl BlockTextures {
+    /// Reads every block texture from `path`, a directory or a zip file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let files = if path.is_dir() {
+            let mut files = vec![];
+            read_dir(path, "", &mut files)?;
+            files
+        } else {
+            read_zip(fs::File::open(path)?)?
+        };
+
+        Ok(Self::from_files(files))
+    }
+
+    /// Decodes the PNGs among `files`, given as paths relative to the
+    /// directory or zip root with `/` between components.
+    pub fn from_files(files: Vec<(String, Vec<u8>)>) -> Self {
+        let mut textures = Self::default();
+
+        for (path, bytes) in files {
+            let Some(name) = texture_name(&path) else {
+                continue;
+            };
+            match png_size(&bytes) {
+                Some((width, height))
+                    if width <= MAX_TEXTURE_SIZE && height <= MAX_TEXTURE_HEIGHT => {}
+                Some((width, height)) => {
+                    log::warn!("Skipping texture {}, it is {}x{}", path, width, height);
+                    continue;
+                }
+                None => {
+                    log::warn!("Skipping texture {}: not a PNG", path);
+                    continue;
+                }
+            }
+            let image = match Image::from_file_with_format(&bytes, Some(ImageFormat::Png)) {
+                Ok(image) => image,
+                Err(err) => {
+                    log::warn!("Skipping texture {}: {}", path, err);
+                    continue;
+                }
+            };
+
+            let size = image.width.min(image.height);
+            let image = image.sub_image(Rect::new(0., 0., size as f32, size as f32));
+            textures.names.insert(name, textures.images.len());
+            textures.images.push(image);
+        }
+
+        textures
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Index into `images` of the texture for the face of `block` with `normal`.
+    pub fn face_texture(&self, block: &str, normal: IVec3) -> Option<usize> {
+        let suffixes: &[&str] = match normal.y {
+            1 => &["_top", ""],
+            -1 => &["_bottom", "_top", ""],
+            _ => &["_side", "", "_front"],
+        };
+
+        if normal.y == -1 {
+            let bottom = BOTTOM_TEXTURES
+                .iter()
+                .find(|(name, _)| *name == block)
+                .and_then(|(_, bottom)| self.names.get(*bottom));
+            if let Some(index) = bottom {
+                return Some(*index);
+            }
+        }
+
+        suffixes
+            .iter()
+            .find_map(|suffix| self.names.get(&format!("{}{}", block, suffix)))
+            .copied()
+    }
+}
+
+/// The color textures of `block` are multiplied with, white for most blocks.
+pub fn tint(block: &str, normal: IVec3) -> Color {
+    let name = block.rsplit(':').next().unwrap_or(block);
+
+    match name {
+        "grass_block" if normal.y == 1 => GRASS,
+        "grass" | "short_grass" | "tall_grass" | "fern" => GRASS,
+        "vine" => FOLIAGE,
+        "water" => WATER,
+        _ if name.ends_with("_leaves") => FOLIAGE,
+        _ => Color::new(1., 1., 1., 1.),
+    }
+}
+
+/// `namespace:name` for PNGs under `assets/<namespace>/textures/block/`,
+/// or `minecraft:name` for PNGs at the root.
+fn texture_name(path: &str) -> Option<String> {
+    let stem = path.strip_suffix(".png")?;
+    let components: Vec<&str> = stem.split('/').collect();
+
+    if let [name] = components[..] {
+        return Some(format!("minecraft:{}", name));
+    }
+
+    let textures = components
+        .iter()
+        .position(|component| *component == "textures")?;
+    let namespace = components.get(textures.checked_sub(1)?)?;
+    match components.get(textures + 1) {
+        // Packs from before 1.13 use `blocks`
+        Some(&"block") | Some(&"blocks") => Some(format!(
+            "{}:{}",
+            namespace,
+            components[textures + 2..].join("/")
+        )),
+        _ => None,
+    }
+}
+
+/// Width and height from a PNG's header, which comes first in the file.
+fn png_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if bytes.get(..8)? != SIGNATURE || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+
+    Some((width, height))
+}
+
+fn read_dir(root: &Path, prefix: &str, files: &mut Vec<(String, Vec<u8>)>) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(prefix))? {
+        let entry = entry?;
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+
+        if entry.file_type()?.is_dir() {
+            read_dir(root, &format!("{}/", path), files)?;
+        } else if path.ends_with(".png") {
+            files.push((path.clone(), fs::read(root.join(&path))?));
+        }
+    }
+
+    Ok(())
+}
+
+/// Larger files in a zip are skipped, no block texture comes near this. It
+/// bounds the compressed PNG only, the size it decodes to is checked apart.
+const MAX_ZIP_ENTRY: u64 = 16 << 20;
+
+/// The PNGs in a zip file.
+fn read_zip(reader: impl Read + Seek) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut archive = ZipArchive::new(reader)?;
+
+    let mut files = vec![];
+    for index in 0..archive.len() {
+        let mut file = match archive.by_index(index) {
+            Ok(file) => file,
+            Err(ZipError::UnsupportedArchive(reason)) => {
+                log::warn!("Skipping zip entry {}: {}", index, reason);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !file.is_file() || !file.name().ends_with(".png") {
+            continue;
+        }
+
+        let mut contents = vec![];
+        (&mut file)
+            .take(MAX_ZIP_ENTRY + 1)
+            .read_to_end(&mut contents)?;
+        if contents.len() as u64 > MAX_ZIP_ENTRY {
+            log::warn!(
+                "Skipping {}, it is larger than {} bytes",
+                file.name(),
+                MAX_ZIP_ENTRY
+            );
+            continue;
+        }
+        files.push((file.name().to_owned(), contents));
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    fn png() -> Vec<u8> {
+        // A 1x1 white PNG
+        vec![
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00,
+            0x00, 0x1f, 0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0x9c, 0x63, 0xf8, 0x0f, 0x04, 0x00, 0x09, 0xfb, 0x03, 0xfd, 0xfb, 0x5e, 0x6b, 0x2b,
+            0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ]
+    }
+
+    /// A zip with every file deflated.
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(io::Cursor::new(vec![]));
+        for (name, contents) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn resource_pack_paths_name_textures() {
+        assert_eq!(
+            texture_name("assets/minecraft/textures/block/oak_log_top.png").as_deref(),
+            Some("minecraft:oak_log_top")
+        );
+        assert_eq!(
+            texture_name("assets/create/textures/blocks/andesite_casing.png").as_deref(),
+            Some("create:andesite_casing")
+        );
+        assert_eq!(
+            texture_name("stone.png").as_deref(),
+            Some("minecraft:stone")
+        );
+        assert_eq!(
+            texture_name("assets/minecraft/textures/item/stick.png"),
+            None
+        );
+        assert_eq!(texture_name("pack.mcmeta"), None);
+    }
+
+    #[test]
+    fn faces_fall_back_to_the_block_texture() {
+        let png = png();
+        let textures = BlockTextures::from_files(
+            [
+                "oak_log",
+                "oak_log_top",
+                "grass_block_top",
+                "grass_block_side",
+                "dirt",
+            ]
+            .iter()
+            .map(|name| (format!("{}.png", name), png.clone()))
+            .collect(),
+        );
+        let index = |name: &str| textures.names[name];
+
+        assert_eq!(
+            textures.face_texture("minecraft:oak_log", IVec3::Y),
+            Some(index("minecraft:oak_log_top"))
+        );
+        assert_eq!(
+            textures.face_texture("minecraft:oak_log", IVec3::NEG_Y),
+            Some(index("minecraft:oak_log_top"))
+        );
+        assert_eq!(
+            textures.face_texture("minecraft:oak_log", IVec3::X),
+            Some(index("minecraft:oak_log"))
+        );
+        assert_eq!(
+            textures.face_texture("minecraft:grass_block", IVec3::NEG_Y),
+            Some(index("minecraft:dirt"))
+        );
+        assert_eq!(
+            textures.face_texture("minecraft:grass_block", IVec3::Z),
+            Some(index("minecraft:grass_block_side"))
+        );
+        assert_eq!(
+            textures.face_texture("minecraft:diamond_ore", IVec3::Y),
+            None
+        );
+        assert_eq!(tint("minecraft:grass_block", IVec3::Y), GRASS);
+        assert_eq!(
+            tint("minecraft:grass_block", IVec3::Z),
+            Color::new(1., 1., 1., 1.)
+        );
+    }
+
+    #[test]
+    fn reads_textures_from_a_zip() {
+        let png = png();
+        let pack = zip(&[
+            ("pack.mcmeta", b"{}"),
+            ("assets/minecraft/textures/block/stone.png", &png),
+        ]);
+
+        let textures = BlockTextures::from_files(read_zip(io::Cursor::new(pack)).unwrap());
+        assert_eq!(textures.len(), 1);
+        assert_eq!(textures.images[0].width, 1);
+        assert!(textures.face_texture("minecraft:stone", IVec3::X).is_some());
+        assert!(read_zip(io::Cursor::new(b"not a zip")).is_err());
+    }
+
+    #[test]
+    fn huge_textures_are_skipped_before_decoding() {
+        assert_eq!(png_size(&png()), Some((1, 1)));
+        assert_eq!(png_size(b"not a png"), None);
+
+        // Too big to decode, the image data only covers one pixel
+        let mut huge = png();
+        huge[16..20].copy_from_slice(&100_000u32.to_be_bytes());
+        huge[20..24].copy_from_slice(&100_000u32.to_be_bytes());
+        let textures = BlockTextures::from_files(vec![
+            ("stone.png".to_owned(), huge),
+            ("dirt.png".to_owned(), png()),
+        ]);
+        assert_eq!(textures.len(), 1);
+        assert!(textures.face_texture("minecraft:stone", IVec3::X).is_none());
+    }
+}
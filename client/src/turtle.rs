@@ -0,0 +1,255 @@
+use macroquad::math::{ivec3, IVec3};
+use protocol::{CommandId, OperatorMessage, TurtleMessage};
+use simple_websockets::{Message, Responder};
+
+use crate::{
+    autopilot::{Autopilot, AutopilotStatus},
+    commands::{Command, CommandState, CommandTable},
+    inventory::TurtleInventory,
+    pose::TurtlePose,
+    world::{self, World},
+};
+
+/// A connected turtle, keyed in [`crate::sockets::Sockets`] by its simple_websockets client id.
+pub struct Turtle {
+    pub client_id: u64,
+    pub responder: Responder,
+    pub computer_id: Option<u32>,
+    pub label: Option<String>,
+    pub commands: CommandTable,
+    /// Where the turtle is, `None` until it reports its pose.
+    pub pose: Option<TurtlePose>,
+    /// Fuel and items, `None` until the turtle reports them.
+    pub inventory: Option<TurtleInventory>,
+    /// Unix time in seconds of the last scan merged into the world.
+    pub last_scan: Option<u64>,
+    /// Set while the turtle is driven to a goal, kept afterwards to show how it went.
+    pub autopilot: Option<Autopilot>,
+}
+
+impl Turtle {
+    pub fn new(client_id: u64, responder: Responder) -> Self {
+        Self {
+            client_id,
+            responder,
+            computer_id: None,
+            label: None,
+            commands: CommandTable::new(),
+            pose: None,
+            inventory: None,
+            last_scan: None,
+            autopilot: None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match (&self.label, self.computer_id) {
+            (Some(label), _) => label.to_owned(),
+            (None, Some(computer_id)) => format!("Turtle #{}", computer_id),
+            (None, None) => format!("Client {}", self.client_id),
+        }
+    }
+
+    /// Where scans from this turtle are placed in the world. Falls back to
+    /// the origin until the turtle reports where it is.
+    pub fn position(&self) -> IVec3 {
+        self.pose.map_or(IVec3::ZERO, |pose| pose.position)
+    }
+
+    /// Sends queued commands and times out those that were never answered.
+    pub fn process(&mut self, now: f64, world: &World) {
+        for id in self.commands.expire(now) {
+            log::error!("{}: command {} timed out", self.name(), id);
+        }
+
+        let name = self.name();
+        if let Some(autopilot) = self.autopilot.as_mut() {
+            let was_running = autopilot.status == AutopilotStatus::Running;
+            let goal = autopilot.goal;
+            match autopilot.update(self.pose, world, &mut self.commands) {
+                AutopilotStatus::Arrived if was_running => {
+                    log::info!("{} arrived at {}", name, goal)
+                }
+                AutopilotStatus::Failed(error) if was_running => {
+                    log::error!("{} could not reach {}: {}", name, goal, error)
+                }
+                _ => (),
+            }
+        }
+
+        for (_, frame) in self.commands.dispatch(now) {
+            self.responder.send(Message::Text(frame));
+        }
+    }
+
+    /// Takes back a command, whether it was sent yet or not.
+    pub fn cancel(&mut self, id: CommandId) {
+        if let Some(frame) = self.commands.cancel(id) {
+            self.responder.send(Message::Text(frame));
+        }
+    }
+
+    /// Drops every command that has not started running, here and on the turtle.
+    pub fn clear_queue(&mut self) {
+        self.commands.clear_queue();
+        self.responder
+            .send(Message::Text(OperatorMessage::Clear.encode()));
+    }
+
+    /// Drives the turtle to `goal`, replacing any route it was following.
+    pub fn go_to(&mut self, goal: IVec3, allow_digging: bool) {
+        log::info!("{}: going to {}", self.name(), goal);
+
+        self.stop();
+        self.autopilot = Some(Autopilot::new(goal, allow_digging));
+    }
+
+    /// Abandons the current route along with any commands not yet running.
+    pub fn stop(&mut self) {
+        self.clear_queue();
+        self.autopilot = None;
+    }
+
+    pub fn message_event(&mut self, msg: &str, world: &mut World) {
+        let message = match TurtleMessage::decode(msg) {
+            Ok(message) => message,
+            Err(err) => {
+                log::error!("Could not decode message from {}: {}", self.name(), err);
+                return;
+            }
+        };
+
+        match message {
+            TurtleMessage::CommandResult { id, values } => match id {
+                Some(id) => self.resolve(id, CommandState::Succeeded(values)),
+                None => log::info!("Command executed successfully {:?}", values),
+            },
+            TurtleMessage::CommandError { id, error } => match id {
+                Some(id) => self.resolve(id, CommandState::Failed(error)),
+                None => log::error!("Turtle does not understand the command! {}", error),
+            },
+            TurtleMessage::Scan {
+                radius,
+                origin,
+                names,
+                blocks,
+            } => {
+                let size = world::scan_radius(blocks.len());
+                let radius = match (radius, size) {
+                    (None, Some(size)) => size,
+                    (Some(radius), Some(size)) if radius == size => radius,
+                    _ => {
+                        log::error!(
+                            "{} sent a scan of {} blocks, which is not a cube of radius {:?}",
+                            self.name(),
+                            blocks.len(),
+                            radius
+                        );
+                        return;
+                    }
+                };
+                // The turtle may have moved since it scanned
+                let origin = origin.map_or(self.position(), IVec3::from_array);
+
+                let seen_at = world::now();
+                world.merge_scan(origin, radius, &names, &blocks, seen_at);
+                self.last_scan = Some(seen_at);
+            }
+            TurtleMessage::SparseScan {
+                radius,
+                origin,
+                names,
+                blocks,
+            } => {
+                if !(0..=world::MAX_SCAN_RADIUS).contains(&radius) {
+                    log::error!(
+                        "{} sent a scan of radius {}, which is not between 0 and {}",
+                        self.name(),
+                        radius,
+                        world::MAX_SCAN_RADIUS
+                    );
+                    return;
+                }
+                let origin = origin.map_or(self.position(), IVec3::from_array);
+
+                let seen_at = world::now();
+                world.merge_sparse_scan(origin, radius, &names, &blocks, seen_at);
+                self.last_scan = Some(seen_at);
+            }
+            TurtleMessage::Queue { running, queued } => self.commands.report_queue(running, queued),
+            TurtleMessage::Heartbeat => log::trace!("{} heartbeat", self.name()),
+            TurtleMessage::Pose {
+                x,
+                y,
+                z,
+                heading,
+                source,
+            } => {
+                let pose = TurtlePose {
+                    position: ivec3(x, y, z),
+                    heading,
+                    source,
+                };
+                if self.pose.is_none_or(|old| old.source != source) {
+                    log::info!("{} position is now tracked by {:?}", self.name(), source);
+                }
+                self.pose = Some(pose);
+            }
+            TurtleMessage::Inventory {
+                fuel,
+                fuel_limit,
+                selected,
+                slots,
+            } => {
+                self.inventory = Some(TurtleInventory {
+                    fuel,
+                    fuel_limit,
+                    selected,
+                    slots,
+                })
+            }
+            TurtleMessage::Status { id, label } => {
+                self.computer_id = id;
+                self.label = label;
+                log::info!("Client {} is {}", self.client_id, self.name());
+            }
+            TurtleMessage::Presence { online, .. } => {
+                log::info!(
+                    "{} is {}",
+                    self.name(),
+                    if online { "online" } else { "offline" }
+                )
+            }
+            TurtleMessage::Unknown => log::warn!("Ignoring unknown turtle message: {}", msg),
+        }
+    }
+
+    fn resolve(&mut self, id: CommandId, state: CommandState) {
+        let name = self.name();
+        match self.commands.resolve(id, state) {
+            Some(Command {
+                code,
+                state: CommandState::Succeeded(values),
+                ..
+            }) => log::info!(
+                "{}: command #{} `{}` executed successfully {:?}",
+                name,
+                id,
+                code,
+                values
+            ),
+            Some(Command {
+                code,
+                state: CommandState::Failed(error),
+                ..
+            }) => log::error!(
+                "{} does not understand command #{} `{}`! {}",
+                name,
+                id,
+                code,
+                error
+            ),
+            _ => log::warn!("{}: got a reply to unknown command #{}", name, id),
+        }
+    }
+}
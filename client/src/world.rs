@@ -0,0 +1,428 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use macroquad::{
+    color::Color,
+    math::{ivec3, IVec3},
+};
+
+pub const CHUNK_SIZE: i32 = 16;
+pub const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// Index into [`World::palette`].
+pub type BlockId = u16;
+/// Never scanned.
+pub const UNKNOWN: BlockId = 0;
+pub const AIR: BlockId = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockType {
+    pub name: String,
+    pub color: Color,
+}
+
+impl BlockType {
+    pub fn new(name: &str) -> Self {
+        let hash: [u8; 16] = md5::compute(name.as_bytes()).into();
+        let color = Color::new(
+            hash[0] as f32 / 255.,
+            hash[1] as f32 / 255.,
+            hash[2] as f32 / 255.,
+            1.0,
+        );
+
+        Self {
+            name: name.to_owned(),
+            color,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Chunk {
+    pub blocks: Vec<BlockId>,
+    /// Unix time in seconds each block was last scanned, 0 if never.
+    pub last_seen: Vec<u64>,
+    /// [`World::revision`] when a block in or next to this chunk last changed.
+    pub revision: u64,
+}
+
+impl Chunk {
+    pub fn index(local: IVec3) -> usize {
+        (local.x + local.z * CHUNK_SIZE + local.y * CHUNK_SIZE * CHUNK_SIZE) as usize
+    }
+
+    pub fn local_coord(index: usize) -> IVec3 {
+        let index = index as i32;
+
+        ivec3(
+            index % CHUNK_SIZE,
+            index / (CHUNK_SIZE * CHUNK_SIZE),
+            (index / CHUNK_SIZE) % CHUNK_SIZE,
+        )
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self {
+            blocks: vec![UNKNOWN; CHUNK_VOLUME],
+            last_seen: vec![0; CHUNK_VOLUME],
+            revision: 0,
+        }
+    }
+}
+
+/// Every block any turtle has scanned, in absolute world coordinates.
+pub struct World {
+    pub chunks: HashMap<IVec3, Chunk>,
+    pub palette: Vec<BlockType>,
+    palette_ids: HashMap<String, BlockId>,
+    /// Set whenever a block changes, cleared once the world has been saved.
+    pub modified: bool,
+    /// Bumped whenever a block is replaced by a different one, so meshes
+    /// built from the blocks know when to rebuild.
+    pub revision: u64,
+}
+
+impl World {
+    pub fn new() -> Self {
+        let mut world = Self {
+            chunks: HashMap::new(),
+            palette: vec![],
+            palette_ids: HashMap::new(),
+            modified: false,
+            revision: 0,
+        };
+        world.block_id("cc-websockets:unknown");
+        world.block_id("minecraft:air");
+
+        world
+    }
+
+    /// Palette id for `name`, adding it to the palette if it is new.
+    pub fn block_id(&mut self, name: &str) -> BlockId {
+        if let Some(id) = self.palette_ids.get(name) {
+            return *id;
+        }
+
+        let id = self.palette.len() as BlockId;
+        self.palette.push(BlockType::new(name));
+        self.palette_ids.insert(name.to_owned(), id);
+
+        id
+    }
+
+    pub fn chunk_coord(coord: IVec3) -> IVec3 {
+        coord.div_euclid(IVec3::splat(CHUNK_SIZE))
+    }
+
+    fn local_coord(coord: IVec3) -> IVec3 {
+        coord.rem_euclid(IVec3::splat(CHUNK_SIZE))
+    }
+
+    pub fn get(&self, coord: IVec3) -> BlockId {
+        match self.chunks.get(&Self::chunk_coord(coord)) {
+            Some(chunk) => chunk.blocks[Chunk::index(Self::local_coord(coord))],
+            None => UNKNOWN,
+        }
+    }
+
+    /// The block at `coord`, `None` for air and unscanned blocks.
+    pub fn solid_block(&self, coord: IVec3) -> Option<&BlockType> {
+        match self.get(coord) {
+            UNKNOWN | AIR => None,
+            id => self.palette.get(id as usize),
+        }
+    }
+
+    pub fn is_solid(&self, coord: IVec3) -> bool {
+        self.get(coord) > AIR
+    }
+
+    pub fn last_seen(&self, coord: IVec3) -> Option<u64> {
+        let chunk = self.chunks.get(&Self::chunk_coord(coord))?;
+
+        match chunk.last_seen[Chunk::index(Self::local_coord(coord))] {
+            0 => None,
+            seen => Some(seen),
+        }
+    }
+
+    pub fn set(&mut self, coord: IVec3, id: BlockId, seen_at: u64) {
+        let chunk_coord = Self::chunk_coord(coord);
+        let local = Self::local_coord(coord);
+        let chunk = self.chunks.entry(chunk_coord).or_default();
+        let index = Chunk::index(local);
+
+        chunk.last_seen[index] = seen_at;
+        self.modified = true;
+        if chunk.blocks[index] == id {
+            return;
+        }
+
+        chunk.blocks[index] = id;
+        self.revision += 1;
+        chunk.revision = self.revision;
+
+        // Faces of the neighbouring chunk may have been uncovered or covered up
+        for axis in [IVec3::X, IVec3::Y, IVec3::Z] {
+            let side = local.dot(axis);
+            let neighbour = match side {
+                0 => chunk_coord - axis,
+                _ if side == CHUNK_SIZE - 1 => chunk_coord + axis,
+                _ => continue,
+            };
+            if let Some(chunk) = self.chunks.get_mut(&neighbour) {
+                chunk.revision = self.revision;
+            }
+        }
+    }
+
+    /// Writes a geo scan centered on `origin` into the world. Blocks the scan
+    /// covers but did not report are air, everything outside it is left alone.
+    pub fn merge_scan(
+        &mut self,
+        origin: IVec3,
+        radius: i32,
+        names: &[String],
+        blocks: &[u16],
+        seen_at: u64,
+    ) {
+        let ids: Vec<BlockId> = names.iter().map(|name| self.block_id(name)).collect();
+        let width = 2 * radius + 1;
+
+        for (block_index, name_index) in blocks.iter().enumerate() {
+            let block_index = block_index as i32;
+            let offset = ivec3(
+                block_index % width,
+                (block_index / width) % width,
+                block_index / (width * width),
+            ) - IVec3::splat(radius);
+
+            let id = match *name_index {
+                0 => AIR,
+                index => match ids.get(index as usize - 1) {
+                    Some(id) => *id,
+                    None => {
+                        log::warn!("Scan references unknown palette index {}", index);
+                        continue;
+                    }
+                },
+            };
+            self.set(origin + offset, id, seen_at);
+        }
+    }
+
+    /// Like [`World::merge_scan`] for scans that only list the blocks that are
+    /// not air, as `[x, y, z, index]` relative to `origin`.
+    pub fn merge_sparse_scan(
+        &mut self,
+        origin: IVec3,
+        radius: i32,
+        names: &[String],
+        blocks: &[[i32; 4]],
+        seen_at: u64,
+    ) {
+        let ids: Vec<BlockId> = names.iter().map(|name| self.block_id(name)).collect();
+
+        let mut found = HashMap::new();
+        for &[x, y, z, index] in blocks {
+            let offset = ivec3(x, y, z);
+            if offset.abs().max_element() > radius {
+                log::warn!("Scan of radius {} lists a block at {}", radius, offset);
+                continue;
+            }
+            match ids.get((index as usize).wrapping_sub(1)) {
+                Some(id) => {
+                    found.insert(offset, *id);
+                }
+                None => log::warn!("Scan references unknown palette index {}", index),
+            }
+        }
+
+        // Every block is set once, blocks that did not change leave the revision alone
+        for z in -radius..=radius {
+            for y in -radius..=radius {
+                for x in -radius..=radius {
+                    let offset = ivec3(x, y, z);
+                    let id = found.get(&offset).copied().unwrap_or(AIR);
+                    self.set(origin + offset, id, seen_at);
+                }
+            }
+        }
+    }
+
+    /// Every scanned block that is not air.
+    pub fn solid_blocks(&self) -> impl Iterator<Item = (IVec3, &BlockType)> {
+        self.chunks.iter().flat_map(move |(chunk_coord, chunk)| {
+            chunk
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, id)| **id > AIR)
+                .map(move |(index, id)| {
+                    (
+                        *chunk_coord * CHUNK_SIZE + Chunk::local_coord(index),
+                        &self.palette[*id as usize],
+                    )
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+impl World {
+    /// A world of nothing but `blocks`, for tests.
+    pub fn with_blocks(blocks: &[(IVec3, &str)]) -> Self {
+        let mut world = World::new();
+        for (coord, name) in blocks {
+            let id = world.block_id(name);
+            world.set(*coord, id, 1);
+        }
+
+        world
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The largest radius a geo scanner can be configured to scan. Sparse scans
+/// state their radius rather than listing every block, so it is checked
+/// against this before the whole cube is cleared.
+pub const MAX_SCAN_RADIUS: i32 = 16;
+
+/// Radius of a geo scan with `len` blocks, `None` if no scan has that many.
+pub fn scan_radius(len: usize) -> Option<i32> {
+    let width = (len as f64).cbrt().round() as usize;
+
+    (width % 2 == 1 && width.pow(3) == len).then_some((width as i32 - 1) / 2)
+}
+
+/// Current unix time in seconds, used for [`Chunk::last_seen`].
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_merge_at_their_origin() {
+        let mut world = World::new();
+        let names = vec!["minecraft:stone".to_string()];
+        // Radius 1 scan with stone directly below the turtle
+        let mut blocks = vec![0; 27];
+        blocks[10] = 1;
+
+        world.merge_scan(ivec3(100, 64, -3), 1, &names, &blocks, 10);
+
+        assert_eq!(
+            world.solid_block(ivec3(100, 63, -3)).unwrap().name,
+            "minecraft:stone"
+        );
+        assert_eq!(world.get(ivec3(100, 64, -3)), AIR);
+        assert_eq!(world.get(ivec3(100, 66, -3)), UNKNOWN);
+        assert_eq!(world.last_seen(ivec3(101, 65, -2)), Some(10));
+        assert_eq!(world.solid_blocks().count(), 1);
+    }
+
+    #[test]
+    fn scan_radius_comes_from_the_block_count() {
+        assert_eq!(scan_radius(1), Some(0));
+        assert_eq!(scan_radius(27), Some(1));
+        assert_eq!(scan_radius(33 * 33 * 33), Some(16));
+        assert_eq!(scan_radius(8), None);
+        assert_eq!(scan_radius(28), None);
+    }
+
+    #[test]
+    fn sparse_scans_merge_like_dense_ones() {
+        let names = vec!["minecraft:stone".to_string()];
+        let mut dense = vec![0; 27];
+        dense[10] = 1;
+        dense[26] = 1;
+
+        let mut expected = World::new();
+        expected.merge_scan(ivec3(100, 64, -3), 1, &names, &dense, 10);
+        let mut world = World::new();
+        world.merge_sparse_scan(
+            ivec3(100, 64, -3),
+            1,
+            &names,
+            &[[0, -1, 0, 1], [1, 1, 1, 1], [2, 0, 0, 1], [0, 0, 1, 2]],
+            10,
+        );
+
+        for x in 98..=102 {
+            for y in 62..=66 {
+                for z in -5..=-1 {
+                    let coord = ivec3(x, y, z);
+                    assert_eq!(world.get(coord), expected.get(coord), "{}", coord);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn later_scans_only_replace_what_they_cover() {
+        let mut world = World::new();
+        let names = vec!["minecraft:stone".to_string()];
+
+        world.merge_scan(IVec3::ZERO, 1, &names, &[1; 27], 10);
+        world.merge_scan(ivec3(2, 0, 0), 1, &names, &[0; 27], 20);
+
+        assert!(world.is_solid(ivec3(0, 0, 0)));
+        assert!(!world.is_solid(ivec3(1, 0, 0)));
+        assert_eq!(world.last_seen(ivec3(1, 0, 0)), Some(20));
+        assert_eq!(world.last_seen(ivec3(-1, 0, 0)), Some(10));
+        assert_eq!(world.solid_blocks().count(), 18);
+    }
+
+    #[test]
+    fn rescanning_the_same_blocks_keeps_the_revision() {
+        let mut world = World::new();
+        let names = vec!["minecraft:stone".to_string()];
+
+        world.merge_sparse_scan(IVec3::ZERO, 1, &names, &[[0, -1, 0, 1]], 10);
+        let revision = world.revision;
+        world.merge_sparse_scan(IVec3::ZERO, 1, &names, &[[0, -1, 0, 1]], 20);
+        assert_eq!(world.revision, revision);
+        assert_eq!(world.last_seen(ivec3(0, -1, 0)), Some(20));
+
+        world.merge_sparse_scan(IVec3::ZERO, 1, &names, &[], 30);
+        assert!(world.revision > revision);
+    }
+
+    #[test]
+    fn changes_on_a_border_touch_the_neighbouring_chunk() {
+        let mut world = World::new();
+        let stone = world.block_id("minecraft:stone");
+        world.set(ivec3(-1, 0, 0), stone, 1);
+        world.set(ivec3(8, 0, 0), stone, 1);
+        let left = world.chunks[&ivec3(-1, 0, 0)].revision;
+
+        world.set(ivec3(8, 1, 0), stone, 2);
+        assert_eq!(world.chunks[&ivec3(-1, 0, 0)].revision, left);
+        world.set(ivec3(0, 0, 0), stone, 3);
+        assert_eq!(world.chunks[&ivec3(-1, 0, 0)].revision, world.revision);
+        assert_eq!(world.chunks[&IVec3::ZERO].revision, world.revision);
+    }
+
+    #[test]
+    fn chunk_indices_round_trip() {
+        for index in [0, 1, 17, 300, CHUNK_VOLUME - 1] {
+            assert_eq!(Chunk::index(Chunk::local_coord(index)), index);
+        }
+    }
+}
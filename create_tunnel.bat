@@ -1 +1 @@
-ngrok http 1234
\ No newline at end of file
+ngrok http 1235
\ No newline at end of file
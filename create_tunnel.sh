@@ -1,2 +1,2 @@
 #!/bin/bash
-ngrok http 1234
\ No newline at end of file
+ngrok http 1235
\ No newline at end of file
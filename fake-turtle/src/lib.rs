@@ -0,0 +1,75 @@
+//! A stand-in for cc-script.lua running on a real turtle, for testing the
+//! client and the server without a Minecraft world.
+
+pub mod turtle;
+pub mod world;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use protocol::TurtleMessage;
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+pub use turtle::FakeTurtle;
+pub use world::FakeWorld;
+
+/// Connects `turtle` to `url` and answers commands until the other side hangs
+/// up. Like cc-script.lua it greets with its status, pose and inventory, then
+/// sends a heartbeat, the inventory and a scan every `scan_interval`.
+///
+/// The turtle is shared so tests can look at or rearrange its world while it
+/// is connected.
+pub async fn run(
+    url: &str,
+    turtle: Arc<Mutex<FakeTurtle>>,
+    scan_interval: Duration,
+) -> Result<(), Error> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await?;
+    log::info!("Connected to {}", url);
+
+    let greeting = {
+        let turtle = turtle.lock().unwrap();
+        vec![turtle.status(), turtle.pose(), turtle.inventory()]
+    };
+    send(&mut socket, greeting).await?;
+
+    let mut timer = tokio::time::interval(scan_interval);
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    log::debug!("Got {}", text);
+                    let replies = turtle.lock().unwrap().handle(&text);
+                    send(&mut socket, replies).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    log::info!("Disconnected from {}", url);
+                    return Ok(());
+                }
+                Some(Ok(_)) => (),
+                Some(Err(err)) => return Err(err),
+            },
+            _ = timer.tick() => {
+                let messages = {
+                    let turtle = turtle.lock().unwrap();
+                    vec![TurtleMessage::Heartbeat, turtle.inventory(), turtle.scan()]
+                };
+                send(&mut socket, messages).await?;
+            }
+        }
+    }
+}
+
+async fn send<S>(socket: &mut S, messages: Vec<TurtleMessage>) -> Result<(), Error>
+where
+    S: SinkExt<Message, Error = Error> + Unpin,
+{
+    for message in messages {
+        socket.send(Message::Text(message.encode())).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Parser;
+use fake_turtle::{FakeTurtle, FakeWorld};
+
+/// Connects a simulated turtle in a superflat world to a client or server.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Websocket to connect to, a client on this machine by default. Use
+    /// port 1234 to connect to a server instead.
+    #[arg(long, default_value = "ws://127.0.0.1:1235")]
+    url: String,
+    #[arg(long, default_value_t = 0)]
+    id: u32,
+    #[arg(long)]
+    label: Option<String>,
+    /// Starting position as x,y,z, standing on the grass by default.
+    #[arg(long, value_delimiter = ',', num_args = 3, default_values_t = [0, 0, 0])]
+    position: Vec<i32>,
+    #[arg(long, default_value_t = fake_turtle::turtle::DEFAULT_SCAN_RADIUS)]
+    scan_radius: i32,
+    /// Send scans as a zero-filled cube like older versions of cc-script.lua.
+    #[arg(long)]
+    dense_scans: bool,
+    /// Seconds between scans.
+    #[arg(long, default_value_t = 2.)]
+    scan_interval: f64,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    std::env::set_var("RUST_LOG", "info");
+    pretty_env_logger::init();
+
+    let mut turtle = FakeTurtle::new(
+        FakeWorld::flat(0),
+        (args.position[0], args.position[1], args.position[2]),
+    );
+    turtle.computer_id = args.id;
+    turtle.label = args.label;
+    turtle.scan_radius = args.scan_radius;
+    turtle.sparse_scans = !args.dense_scans;
+
+    let turtle = Arc::new(Mutex::new(turtle));
+    if let Err(err) = fake_turtle::run(
+        &args.url,
+        turtle,
+        Duration::from_secs_f64(args.scan_interval),
+    )
+    .await
+    {
+        log::error!("Connection to {} failed: {}", args.url, err);
+    }
+}
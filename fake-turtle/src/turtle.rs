@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+
+use protocol::{Heading, ItemStack, OperatorMessage, PositionSource, TurtleMessage, Value};
+use serde_json::json;
+
+use crate::world::{Coord, FakeWorld, BEDROCK};
+
+/// Scan radius of the ComputerCraft geo scanner cc-script.lua uses.
+pub const DEFAULT_SCAN_RADIUS: i32 = 8;
+/// Inventory slots of a turtle.
+pub const SLOTS: usize = 16;
+/// Fuel a normal turtle holds at most.
+pub const FUEL_LIMIT: u32 = 20000;
+
+/// Plays the part of cc-script.lua running on a turtle with GPS and a geo
+/// scanner. Commands are not run as Lua, only `turtle.<function>()` calls
+/// from the movement, dig and inspect subset of the turtle API are understood.
+#[derive(Clone, Debug)]
+pub struct FakeTurtle {
+    pub world: FakeWorld,
+    pub position: Coord,
+    pub heading: Heading,
+    pub computer_id: u32,
+    pub label: Option<String>,
+    pub scan_radius: i32,
+    /// Send scans as `sparse_scan` like cc-script.lua, or as the dense `scan` older scripts sent.
+    pub sparse_scans: bool,
+    /// Fuel left, `None` when fuel is turned off. Every move takes one.
+    pub fuel: Option<u32>,
+    pub slots: [Option<ItemStack>; SLOTS],
+    /// 1-based like `turtle.getSelectedSlot`.
+    pub selected: u8,
+    /// Set when the turtle moved or turned since its pose was last sent.
+    pose_changed: bool,
+}
+
+impl FakeTurtle {
+    pub fn new(world: FakeWorld, position: Coord) -> Self {
+        Self {
+            world,
+            position,
+            heading: Heading::North,
+            computer_id: 0,
+            label: None,
+            scan_radius: DEFAULT_SCAN_RADIUS,
+            sparse_scans: true,
+            fuel: None,
+            slots: Default::default(),
+            selected: 1,
+            pose_changed: false,
+        }
+    }
+
+    pub fn status(&self) -> TurtleMessage {
+        TurtleMessage::Status {
+            id: Some(self.computer_id),
+            label: self.label.clone(),
+        }
+    }
+
+    pub fn pose(&self) -> TurtleMessage {
+        let (x, y, z) = self.position;
+
+        TurtleMessage::Pose {
+            x,
+            y,
+            z,
+            heading: self.heading,
+            source: PositionSource::Gps,
+        }
+    }
+
+    pub fn inventory(&self) -> TurtleMessage {
+        TurtleMessage::Inventory {
+            fuel: self.fuel,
+            fuel_limit: self.fuel.map(|_| FUEL_LIMIT),
+            selected: self.selected,
+            slots: self.slots.to_vec(),
+        }
+    }
+
+    /// Geo scan centered on the turtle, encoded like `serialize` in cc-script.lua.
+    pub fn scan(&self) -> TurtleMessage {
+        let r = self.scan_radius;
+        let (x, y, z) = self.position;
+
+        let mut names: Vec<String> = vec![];
+        let mut indices: HashMap<&str, u16> = HashMap::new();
+        let mut found = vec![];
+
+        for dz in -r..=r {
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    // The scanner does not see the turtle itself
+                    if (dx, dy, dz) == (0, 0, 0) {
+                        continue;
+                    }
+                    if let Some(name) = self.world.get((x + dx, y + dy, z + dz)) {
+                        let index = *indices.entry(name).or_insert_with(|| {
+                            names.push(name.to_owned());
+                            names.len() as u16
+                        });
+                        found.push([dx, dy, dz, index as i32]);
+                    }
+                }
+            }
+        }
+
+        if self.sparse_scans {
+            return TurtleMessage::SparseScan {
+                radius: r,
+                origin: Some([x, y, z]),
+                names,
+                blocks: found,
+            };
+        }
+
+        let w = 2 * r + 1;
+        let mut blocks = vec![0; (w * w * w) as usize];
+        for [dx, dy, dz, index] in found {
+            blocks[((dz + r) * w * w + (dy + r) * w + (dx + r)) as usize] = index as u16;
+        }
+
+        TurtleMessage::Scan {
+            radius: Some(r),
+            origin: Some([x, y, z]),
+            names,
+            blocks,
+        }
+    }
+
+    /// Answers a frame from the controlling side with the messages
+    /// cc-script.lua would send back: the new pose if the command moved the
+    /// turtle, the inventory, then the command's result or error. Commands run as soon as
+    /// they arrive, so the queue is always empty when cancel or clear come in.
+    pub fn handle(&mut self, text: &str) -> Vec<TurtleMessage> {
+        // Like cc-script.lua, anything that is not a command frame is run as raw Lua
+        let (id, code) = match OperatorMessage::decode(text) {
+            Ok(OperatorMessage::Command { id, code }) => (Some(id), code),
+            Ok(OperatorMessage::Cancel { id }) => {
+                return vec![TurtleMessage::CommandError {
+                    id: Some(id),
+                    error: format!("no command {} in the queue", id),
+                }]
+            }
+            Ok(OperatorMessage::Clear) => {
+                return vec![TurtleMessage::Queue {
+                    running: None,
+                    queued: vec![],
+                }]
+            }
+            Err(_) => (None, text.to_owned()),
+        };
+
+        let result = self.run(&code);
+
+        let mut replies = vec![];
+        if self.pose_changed {
+            self.pose_changed = false;
+            replies.push(self.pose());
+        }
+        replies.push(self.inventory());
+        replies.push(match result {
+            Ok(values) => TurtleMessage::CommandResult { id, values },
+            Err(error) => TurtleMessage::CommandError { id, error },
+        });
+
+        replies
+    }
+
+    /// Runs `turtle.<function>()` or `return turtle.<function>()`, returning
+    /// what the Lua chunk would return or the error it would raise.
+    pub fn run(&mut self, code: &str) -> Result<Vec<Value>, String> {
+        let code = code.trim();
+        let (returns, call) = match code.strip_prefix("return ") {
+            Some(call) => (true, call.trim()),
+            None => (false, code),
+        };
+        let Some(function) = call
+            .strip_prefix("turtle.")
+            .and_then(|call| call.strip_suffix("()"))
+        else {
+            return Err(format!("fake turtle can not run `{}`", code));
+        };
+
+        let values = match function {
+            "forward" => self.step(self.forward()),
+            "back" => self.step(self.back()),
+            "up" => self.step(self.up()),
+            "down" => self.step(self.down()),
+            "turnLeft" => self.turn(self.heading.turn_left()),
+            "turnRight" => self.turn(self.heading.turn_right()),
+            "dig" => self.dig(self.forward()),
+            "digUp" => self.dig(self.up()),
+            "digDown" => self.dig(self.down()),
+            "detect" => self.detect(self.forward()),
+            "detectUp" => self.detect(self.up()),
+            "detectDown" => self.detect(self.down()),
+            "inspect" => self.inspect(self.forward()),
+            "inspectUp" => self.inspect(self.up()),
+            "inspectDown" => self.inspect(self.down()),
+            _ => {
+                return Err(format!(
+                    "attempt to call field '{}' (a nil value)",
+                    function
+                ))
+            }
+        };
+
+        Ok(if returns { values } else { vec![] })
+    }
+
+    fn forward(&self) -> Coord {
+        let (x, y, z) = self.position;
+        let (dx, dz) = self.heading.step();
+
+        (x + dx, y, z + dz)
+    }
+
+    fn back(&self) -> Coord {
+        let (x, y, z) = self.position;
+        let (dx, dz) = self.heading.step();
+
+        (x - dx, y, z - dz)
+    }
+
+    fn up(&self) -> Coord {
+        let (x, y, z) = self.position;
+        (x, y + 1, z)
+    }
+
+    fn down(&self) -> Coord {
+        let (x, y, z) = self.position;
+        (x, y - 1, z)
+    }
+
+    fn step(&mut self, to: Coord) -> Vec<Value> {
+        if self.world.get(to).is_some() {
+            return vec![false.into(), "Movement obstructed".into()];
+        }
+        match self.fuel {
+            Some(0) => return vec![false.into(), "Out of fuel".into()],
+            Some(fuel) => self.fuel = Some(fuel - 1),
+            None => (),
+        }
+
+        self.position = to;
+        self.pose_changed = true;
+        vec![true.into()]
+    }
+
+    fn turn(&mut self, heading: Heading) -> Vec<Value> {
+        self.heading = heading;
+        self.pose_changed = true;
+
+        vec![true.into()]
+    }
+
+    fn dig(&mut self, at: Coord) -> Vec<Value> {
+        match self.world.get(at) {
+            None => vec![false.into(), "Nothing to dig here".into()],
+            Some(BEDROCK) => vec![false.into(), "Cannot break unbreakable block".into()],
+            Some(_) => {
+                self.world.set(at, None);
+                vec![true.into()]
+            }
+        }
+    }
+
+    fn detect(&self, at: Coord) -> Vec<Value> {
+        vec![self.world.get(at).is_some().into()]
+    }
+
+    fn inspect(&self, at: Coord) -> Vec<Value> {
+        match self.world.get(at) {
+            None => vec![false.into(), "No block to inspect".into()],
+            Some(name) => vec![
+                true.into(),
+                json!({ "name": name, "state": {}, "tags": {} }),
+            ],
+        }
+    }
+}
+
+impl Default for FakeTurtle {
+    fn default() -> Self {
+        Self::new(FakeWorld::default(), (0, 0, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::CommandId;
+
+    fn command(id: CommandId, code: &str) -> String {
+        OperatorMessage::Command {
+            id,
+            code: code.to_owned(),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn moves_report_their_pose_before_the_result() {
+        let mut turtle = FakeTurtle::default();
+
+        let replies = turtle.handle(&command(1, "return turtle.forward()"));
+        assert_eq!(
+            replies,
+            vec![
+                TurtleMessage::Pose {
+                    x: 0,
+                    y: 0,
+                    z: -1,
+                    heading: Heading::North,
+                    source: PositionSource::Gps,
+                },
+                turtle.inventory(),
+                TurtleMessage::CommandResult {
+                    id: Some(1),
+                    values: vec![Value::Bool(true)],
+                },
+            ]
+        );
+
+        // Grass below, moving into it fails without a pose update
+        assert_eq!(
+            turtle.handle(&command(2, "return turtle.down()")),
+            vec![
+                turtle.inventory(),
+                TurtleMessage::CommandResult {
+                    id: Some(2),
+                    values: vec![Value::Bool(false), Value::from("Movement obstructed")],
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn digging_clears_the_way() {
+        let mut turtle = FakeTurtle::default();
+
+        assert_eq!(
+            turtle.run("return turtle.inspectDown()").unwrap()[1]["name"],
+            "minecraft:grass_block"
+        );
+        assert_eq!(
+            turtle.run("return turtle.digDown()"),
+            Ok(vec![Value::Bool(true)])
+        );
+        assert_eq!(
+            turtle.run("return turtle.down()"),
+            Ok(vec![Value::Bool(true)])
+        );
+        assert_eq!(turtle.position, (0, -1, 0));
+        assert_eq!(
+            turtle.run("return turtle.detectUp()"),
+            Ok(vec![Value::Bool(false)])
+        );
+    }
+
+    #[test]
+    fn raw_lua_and_unknown_functions() {
+        let mut turtle = FakeTurtle::default();
+
+        assert_eq!(
+            turtle.handle("turtle.turnLeft()"),
+            vec![
+                turtle.pose(),
+                turtle.inventory(),
+                TurtleMessage::CommandResult {
+                    id: None,
+                    values: vec![]
+                }
+            ]
+        );
+        assert_eq!(turtle.heading, Heading::West);
+        assert!(matches!(
+            &turtle.handle(&command(3, "return turtle.refuel()"))[..],
+            [_, TurtleMessage::CommandError { id: Some(3), .. }]
+        ));
+    }
+
+    #[test]
+    fn moves_burn_fuel() {
+        let mut turtle = FakeTurtle {
+            fuel: Some(1),
+            ..FakeTurtle::default()
+        };
+
+        assert_eq!(
+            turtle.run("return turtle.up()"),
+            Ok(vec![Value::Bool(true)])
+        );
+        assert_eq!(
+            turtle.run("return turtle.up()"),
+            Ok(vec![Value::Bool(false), Value::from("Out of fuel")])
+        );
+        assert!(matches!(
+            turtle.inventory(),
+            TurtleMessage::Inventory { fuel: Some(0), .. }
+        ));
+    }
+
+    #[test]
+    fn nothing_is_ever_queued() {
+        let mut turtle = FakeTurtle::default();
+
+        assert!(matches!(
+            &turtle.handle(&OperatorMessage::Cancel { id: 4 }.encode())[..],
+            [TurtleMessage::CommandError { id: Some(4), .. }]
+        ));
+        assert_eq!(
+            turtle.handle(&OperatorMessage::Clear.encode()),
+            vec![TurtleMessage::Queue {
+                running: None,
+                queued: vec![]
+            }]
+        );
+    }
+
+    #[test]
+    fn scans_use_the_lua_layout() {
+        let turtle = FakeTurtle {
+            scan_radius: 1,
+            sparse_scans: false,
+            ..FakeTurtle::default()
+        };
+
+        let TurtleMessage::Scan {
+            radius,
+            origin,
+            names,
+            blocks,
+        } = turtle.scan()
+        else {
+            unreachable!()
+        };
+        assert_eq!((radius, origin), (Some(1), Some([0, 0, 0])));
+        assert_eq!(names, vec!["minecraft:grass_block".to_owned()]);
+        assert_eq!(blocks.len(), 27);
+        // (z + r) * w * w + (y + r) * w + (x + r), the bottom layer is grass
+        assert_eq!(blocks.iter().filter(|block| **block == 1).count(), 9);
+        assert_eq!(blocks[10], 1);
+        assert_eq!(blocks[13], 0);
+    }
+
+    #[test]
+    fn sparse_scans_only_list_blocks() {
+        let turtle = FakeTurtle {
+            scan_radius: 1,
+            ..FakeTurtle::default()
+        };
+
+        let TurtleMessage::SparseScan {
+            radius,
+            names,
+            blocks,
+            ..
+        } = turtle.scan()
+        else {
+            unreachable!()
+        };
+        assert_eq!(radius, 1);
+        assert_eq!(names, vec!["minecraft:grass_block".to_owned()]);
+        assert_eq!(blocks.len(), 9);
+        assert!(blocks.contains(&[0, -1, 0, 1]));
+        assert!(blocks.iter().all(|block| block[1] == -1));
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+/// Block position in world coordinates, as `(x, y, z)`.
+pub type Coord = (i32, i32, i32);
+
+pub const BEDROCK: &str = "minecraft:bedrock";
+
+/// An endless superflat world: grass on top of a few layers of dirt, then
+/// stone down to a bedrock floor. Anything placed or dug is kept on top.
+#[derive(Clone, Debug)]
+pub struct FakeWorld {
+    /// Height of the first air layer above the grass, `None` for a world
+    /// without terrain.
+    pub ground: Option<i32>,
+    /// Blocks that differ from the generated terrain, `None` where it was dug out.
+    changes: HashMap<Coord, Option<String>>,
+}
+
+impl FakeWorld {
+    pub fn flat(ground: i32) -> Self {
+        Self {
+            ground: Some(ground),
+            changes: HashMap::new(),
+        }
+    }
+
+    /// A world with nothing in it but what is placed with [`FakeWorld::set`].
+    pub fn empty() -> Self {
+        Self {
+            ground: None,
+            changes: HashMap::new(),
+        }
+    }
+
+    fn generated(&self, (_, y, _): Coord) -> Option<&'static str> {
+        match self.ground?.saturating_sub(y) {
+            i32::MIN..=0 => None,
+            1 => Some("minecraft:grass_block"),
+            2..=4 => Some("minecraft:dirt"),
+            5..=63 => Some("minecraft:stone"),
+            _ => Some(BEDROCK),
+        }
+    }
+
+    /// Name of the block at `coord`, `None` for air.
+    pub fn get(&self, coord: Coord) -> Option<&str> {
+        match self.changes.get(&coord) {
+            Some(block) => block.as_deref(),
+            None => self.generated(coord),
+        }
+    }
+
+    pub fn set(&mut self, coord: Coord, block: Option<&str>) {
+        self.changes.insert(coord, block.map(str::to_owned));
+    }
+}
+
+impl Default for FakeWorld {
+    fn default() -> Self {
+        Self::flat(0)
+    }
+}
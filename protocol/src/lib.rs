@@ -0,0 +1,439 @@
+use serde::{Deserialize, Serialize};
+
+pub use serde_json::Value;
+
+/// Bumped whenever a change to the message layout would break an older peer.
+/// Adding a new message type does not require a bump, unknown types decode as
+/// [`TurtleMessage::Unknown`].
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Every frame on the wire is a JSON object with a `version` and a `type` field,
+/// e.g. `{"version":1,"type":"command_error","error":"attempt to call nil"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Frame<T> {
+    pub version: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Frame<T> {
+    pub fn new(message: T) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message,
+        }
+    }
+}
+
+/// Chosen by the sender of a command and echoed back by the turtle in its reply.
+pub type CommandId = u32;
+
+/// Messages sent to a turtle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OperatorMessage {
+    /// Lua source for the turtle to `loadstring` and run once the commands
+    /// queued before it have finished.
+    Command { id: CommandId, code: String },
+    /// Drops a queued command or stops the running one, which is then
+    /// answered with a `command_error`.
+    Cancel { id: CommandId },
+    /// Drops every queued command, leaving the running one alone. The relay
+    /// server turns it into a `cancel` for each of the sender's own queued
+    /// commands, so other operators keep theirs.
+    Clear,
+}
+
+impl OperatorMessage {
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        decode(text)
+    }
+
+    pub fn encode(&self) -> String {
+        encode(self)
+    }
+}
+
+/// Frames between operators and the relay server carry the turtle they are
+/// for or from next to the message itself, e.g.
+/// `{"version":1,"turtle":"miner","type":"command","id":1,"code":"return turtle.dig()"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Routed<T> {
+    pub turtle: String,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T: Serialize> Routed<T> {
+    pub fn encode(&self) -> String {
+        encode(self)
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> Routed<T> {
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        decode(text)
+    }
+}
+
+/// The direction a turtle faces, named after Minecraft's compass where north is -Z.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Heading {
+    #[default]
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Heading {
+    /// Unit step in world coordinates, as `(x, z)`.
+    pub fn step(self) -> (i32, i32) {
+        match self {
+            Heading::North => (0, -1),
+            Heading::East => (1, 0),
+            Heading::South => (0, 1),
+            Heading::West => (-1, 0),
+        }
+    }
+
+    pub fn turn_right(self) -> Self {
+        match self {
+            Heading::North => Heading::East,
+            Heading::East => Heading::South,
+            Heading::South => Heading::West,
+            Heading::West => Heading::North,
+        }
+    }
+
+    pub fn turn_left(self) -> Self {
+        self.turn_right().turn_right().turn_right()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionSource {
+    /// Located with `gps.locate`, absolute world coordinates.
+    Gps,
+    /// Counted from successful moves since the script started, relative to
+    /// wherever the turtle was at that point.
+    DeadReckoning,
+}
+
+/// A stack of items in one of the turtle's 16 inventory slots, as
+/// `turtle.getItemDetail` describes it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ItemStack {
+    pub name: String,
+    pub count: u32,
+}
+
+/// Messages sent from a turtle (cc-script.lua) to whoever is controlling it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TurtleMessage {
+    /// A command ran without raising an error. `values` holds whatever the
+    /// chunk returned, e.g. `[false, "Movement obstructed"]` for `return turtle.forward()`.
+    /// `id` is missing when the command was sent as raw Lua text.
+    CommandResult {
+        #[serde(default)]
+        id: Option<CommandId>,
+        #[serde(default)]
+        values: Vec<Value>,
+    },
+    /// A command raised an error, or could not be loaded at all.
+    CommandError {
+        #[serde(default)]
+        id: Option<CommandId>,
+        error: String,
+    },
+    /// Geo scanner data. `names` is the palette and `blocks` holds one
+    /// 1-based palette index per block of the scan cube, 0 meaning air.
+    /// Older scripts leave out `radius` and `origin`, the position the turtle
+    /// scanned from, in which case they follow from the size of `blocks` and
+    /// the turtle's last pose.
+    Scan {
+        #[serde(default)]
+        radius: Option<i32>,
+        #[serde(default)]
+        origin: Option<[i32; 3]>,
+        names: Vec<String>,
+        blocks: Vec<u16>,
+    },
+    /// Geo scanner data listing only the blocks that are not air, as
+    /// `[x, y, z, index]` relative to `origin` with a 1-based index into
+    /// `names`. Everything else within `radius` is air.
+    SparseScan {
+        radius: i32,
+        #[serde(default)]
+        origin: Option<[i32; 3]>,
+        names: Vec<String>,
+        blocks: Vec<[i32; 4]>,
+    },
+    Heartbeat,
+    /// Where the turtle is, sent after connecting and whenever it moved or turned.
+    Pose {
+        x: i32,
+        y: i32,
+        z: i32,
+        heading: Heading,
+        source: PositionSource,
+    },
+    /// The turtle's command queue, sent whenever it changes. `queued` is in
+    /// the order the commands will run in.
+    Queue {
+        #[serde(default)]
+        running: Option<CommandId>,
+        #[serde(default)]
+        queued: Vec<CommandId>,
+    },
+    /// Fuel and inventory, sent with every heartbeat and after each command.
+    /// `fuel` and `fuel_limit` are missing when the server does not use fuel.
+    /// `slots` holds all 16 slots in order, `null` for empty ones, and
+    /// `selected` is 1-based like `turtle.getSelectedSlot`.
+    Inventory {
+        #[serde(default)]
+        fuel: Option<u32>,
+        #[serde(default)]
+        fuel_limit: Option<u32>,
+        selected: u8,
+        slots: Vec<Option<ItemStack>>,
+    },
+    /// Sent once after connecting.
+    Status {
+        id: Option<u32>,
+        label: Option<String>,
+    },
+    /// Sent by the relay server on the turtle's behalf when it connects or
+    /// disconnects. `last_seen` is in unix seconds.
+    Presence {
+        online: bool,
+        last_seen: u64,
+    },
+    /// Any message type this build does not know about yet.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Json(err) => write!(f, "malformed frame: {err}"),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version} (newest known is {PROTOCOL_VERSION})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        DecodeError::Json(err)
+    }
+}
+
+impl TurtleMessage {
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        decode(text)
+    }
+
+    pub fn encode(&self) -> String {
+        encode(self)
+    }
+}
+
+pub fn decode<T: serde::de::DeserializeOwned>(text: &str) -> Result<T, DecodeError> {
+    let frame: Frame<T> = serde_json::from_str(text)?;
+
+    if frame.version == 0 || frame.version > PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(frame.version));
+    }
+
+    Ok(frame.message)
+}
+
+pub fn encode<T: Serialize>(message: &T) -> String {
+    serde_json::to_string(&Frame::new(message)).expect("protocol messages always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_round_trip() {
+        let message = TurtleMessage::Scan {
+            radius: Some(0),
+            origin: Some([5, 64, -3]),
+            names: vec!["minecraft:stone".to_string()],
+            blocks: vec![1],
+        };
+
+        assert_eq!(TurtleMessage::decode(&message.encode()).unwrap(), message);
+
+        let msg = r#"{"version":1,"type":"scan","names":["minecraft:stone"],"blocks":[1]}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::Scan {
+                radius: None,
+                origin: None,
+                names: vec!["minecraft:stone".to_string()],
+                blocks: vec![1],
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_sparse_lua_scans() {
+        let msg = r#"{"version":1,"type":"sparse_scan","radius":8,"origin":[1,2,3],"names":["minecraft:stone"],"blocks":[[0,-1,0,1]]}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::SparseScan {
+                radius: 8,
+                origin: Some([1, 2, 3]),
+                names: vec!["minecraft:stone".to_string()],
+                blocks: vec![[0, -1, 0, 1]],
+            }
+        );
+    }
+
+    #[test]
+    fn command_round_trip() {
+        let message = OperatorMessage::Command {
+            id: 7,
+            code: "return turtle.forward()".to_string(),
+        };
+
+        assert_eq!(OperatorMessage::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn queue_control_round_trip() {
+        for message in [OperatorMessage::Cancel { id: 3 }, OperatorMessage::Clear] {
+            assert_eq!(OperatorMessage::decode(&message.encode()).unwrap(), message);
+        }
+        // Lua leaves out nil fields
+        assert_eq!(
+            TurtleMessage::decode(r#"{"version":1,"type":"queue","queued":[2,3]}"#).unwrap(),
+            TurtleMessage::Queue {
+                running: None,
+                queued: vec![2, 3]
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_lua_frames() {
+        let msg = r#"{"version":1,"type":"command_error","id":3,"error":"attempt to call nil"}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::CommandError {
+                id: Some(3),
+                error: "attempt to call nil".to_string()
+            }
+        );
+        let msg = r#"{"version":1,"type":"command_result","id":4,"values":[false,"Movement obstructed"]}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::CommandResult {
+                id: Some(4),
+                values: vec![Value::Bool(false), Value::from("Movement obstructed")]
+            }
+        );
+        assert_eq!(
+            TurtleMessage::decode(r#"{"version":1,"type":"command_result"}"#).unwrap(),
+            TurtleMessage::CommandResult {
+                id: None,
+                values: vec![]
+            }
+        );
+        assert_eq!(
+            TurtleMessage::decode(r#"{"type":"heartbeat","version":1}"#).unwrap(),
+            TurtleMessage::Heartbeat
+        );
+    }
+
+    #[test]
+    fn decodes_pose() {
+        let msg =
+            r#"{"version":1,"type":"pose","x":-120,"y":64,"z":33,"heading":"west","source":"gps"}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::Pose {
+                x: -120,
+                y: 64,
+                z: 33,
+                heading: Heading::West,
+                source: PositionSource::Gps
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_lua_inventory() {
+        let msg = r#"{"version":1,"type":"inventory","selected":2,"slots":[null,{"name":"minecraft:coal","count":12}]}"#;
+        assert_eq!(
+            TurtleMessage::decode(msg).unwrap(),
+            TurtleMessage::Inventory {
+                fuel: None,
+                fuel_limit: None,
+                selected: 2,
+                slots: vec![
+                    None,
+                    Some(ItemStack {
+                        name: "minecraft:coal".to_string(),
+                        count: 12
+                    })
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn routed_frames_are_flat() {
+        let message = Routed {
+            turtle: "miner".to_string(),
+            message: OperatorMessage::Command {
+                id: 1,
+                code: "return turtle.dig()".to_string(),
+            },
+        };
+        let msg = r#"{"version":1,"turtle":"miner","type":"command","id":1,"code":"return turtle.dig()"}"#;
+
+        assert_eq!(Routed::decode(msg).unwrap(), message);
+        assert_eq!(Routed::decode(&message.encode()).unwrap(), message);
+        assert_eq!(
+            Routed::<TurtleMessage>::decode(r#"{"version":1,"turtle":"miner","type":"heartbeat"}"#)
+                .unwrap()
+                .message,
+            TurtleMessage::Heartbeat
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_not_errors() {
+        let msg = r#"{"version":1,"type":"from_the_future","payload":[1,2,3]}"#;
+        assert_eq!(TurtleMessage::decode(msg).unwrap(), TurtleMessage::Unknown);
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        assert!(TurtleMessage::decode("2[\"minecraft:stone\"][0,1]").is_err());
+        assert!(TurtleMessage::decode(r#"{"type":"heartbeat"}"#).is_err());
+        assert!(matches!(
+            TurtleMessage::decode(r#"{"version":99,"type":"heartbeat"}"#),
+            Err(DecodeError::UnsupportedVersion(99))
+        ));
+    }
+}
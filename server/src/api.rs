@@ -0,0 +1,205 @@
+//! REST endpoints for scripts and dashboards that do not want to hold a websocket.
+
+use axum::extract::ws::Message;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use protocol::{OperatorMessage, Routed, TurtleMessage};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::auth::Operator;
+use crate::run::disconnected;
+use crate::turtle_manager::{Reply, TurtleId, TurtleRegistry, TurtleRequest};
+
+/// How long `POST /turtles/{id}/command` waits for the turtle to answer.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub(crate) struct CommandBody {
+    code: String,
+}
+
+pub(crate) fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+fn forbidden(id: &str) -> Response {
+    error(StatusCode::FORBIDDEN, format!("not allowed to access {id}"))
+}
+
+/// `GET /turtles`, every turtle seen since the server started that the token gives access to.
+pub(crate) async fn list_turtles(
+    Operator(scope): Operator,
+    State(app_state): State<TurtleRegistry>,
+) -> Response {
+    let mut turtles = app_state.list().await;
+    turtles.retain(|info| scope.allows(&info.id));
+    Json(turtles).into_response()
+}
+
+/// `GET /turtles/{id}`
+pub(crate) async fn get_turtle(
+    Path(id): Path<String>,
+    Operator(scope): Operator,
+    State(app_state): State<TurtleRegistry>,
+) -> Response {
+    if !scope.allows(&id) {
+        return forbidden(&id);
+    }
+
+    match app_state
+        .list()
+        .await
+        .into_iter()
+        .find(|info| info.id == id)
+    {
+        Some(info) => Json(info).into_response(),
+        None => error(StatusCode::NOT_FOUND, format!("no turtle {id}")),
+    }
+}
+
+/// `POST /turtles/{id}/command` with `{"code": "return turtle.forward()"}`.
+/// Answers with the turtle's `command_result` or `command_error` message, 502
+/// if the turtle disconnects before answering, or 504 if it takes too long.
+pub(crate) async fn command_turtle(
+    Path(id): Path<String>,
+    Operator(scope): Operator,
+    State(app_state): State<TurtleRegistry>,
+    Json(body): Json<CommandBody>,
+) -> Response {
+    if !scope.allows(&id) {
+        return forbidden(&id);
+    }
+    let turtle_id = TurtleId(id.clone());
+    let Some(turtle) = app_state.get(turtle_id.clone()).await else {
+        return error(StatusCode::NOT_FOUND, format!("{id} is not connected"));
+    };
+
+    run_command(&turtle, &turtle_id, body.code, COMMAND_TIMEOUT).await
+}
+
+/// Sends `code` to the turtle's socket task and waits up to `timeout` for the
+/// answer, cancelling the command if it does not come.
+async fn run_command(
+    turtle: &mpsc::UnboundedSender<TurtleRequest>,
+    turtle_id: &TurtleId,
+    code: String,
+    timeout: Duration,
+) -> Response {
+    // The reply comes back on a channel only this request listens to, so the id does not matter
+    let (reply_to, mut replies) = mpsc::unbounded_channel();
+    let request = TurtleRequest {
+        message: OperatorMessage::Command { id: 0, code },
+        reply_to: reply_to.clone(),
+    };
+    if turtle.send(request).is_err() {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("{} is not connected", turtle_id.0),
+        );
+    }
+
+    match tokio::time::timeout(timeout, next_reply(turtle_id, &mut replies)).await {
+        Ok(Ok(reply)) => Json(reply).into_response(),
+        Ok(Err(err)) => error(StatusCode::BAD_GATEWAY, err),
+        Err(_) => {
+            // Nobody will be waiting for the answer, so the turtle may as well not run it
+            let cancel = TurtleRequest {
+                message: OperatorMessage::Cancel { id: 0 },
+                reply_to,
+            };
+            let _ = turtle.send(cancel);
+            error(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("{} did not answer within {timeout:?}", turtle_id.0),
+            )
+        }
+    }
+}
+
+/// The command's result or error, skipping the queue updates before it. Errors
+/// are for when the turtle never answered, as opposed to an error it sent.
+async fn next_reply(
+    turtle_id: &TurtleId,
+    replies: &mut mpsc::UnboundedReceiver<Reply>,
+) -> Result<TurtleMessage, String> {
+    loop {
+        let frame = match replies.recv().await {
+            Some(Reply::Frame(Message::Text(frame))) => frame,
+            Some(Reply::Frame(_)) => continue,
+            Some(Reply::Disconnected { .. }) | None => return Err(disconnected(turtle_id)),
+        };
+        match Routed::<TurtleMessage>::decode(&frame) {
+            Ok(Routed {
+                message: TurtleMessage::Queue { .. },
+                ..
+            }) => continue,
+            Ok(reply) => return Ok(reply.message),
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn commands_that_time_out_are_cancelled() {
+        let (turtle, mut requests) = mpsc::unbounded_channel();
+        let turtle_id = TurtleId("turtle1".to_owned());
+
+        let response = run_command(
+            &turtle,
+            &turtle_id,
+            "sleep(60)".to_owned(),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let command = requests.recv().await.unwrap();
+        assert!(matches!(
+            command.message,
+            OperatorMessage::Command { id: 0, .. }
+        ));
+        let cancel = requests.recv().await.unwrap();
+        assert!(matches!(cancel.message, OperatorMessage::Cancel { id: 0 }));
+        // On the same channel, so the socket task finds the command to cancel
+        assert!(cancel.reply_to.same_channel(&command.reply_to));
+    }
+
+    #[tokio::test]
+    async fn disconnects_are_not_mistaken_for_turtle_errors() {
+        let turtle_id = TurtleId("turtle1".to_owned());
+        let (reply_to, mut replies) = mpsc::unbounded_channel();
+        // A turtle is free to send the same text as the server's own error
+        let error = Routed {
+            turtle: turtle_id.0.clone(),
+            message: TurtleMessage::CommandError {
+                id: Some(0),
+                error: disconnected(&turtle_id),
+            },
+        };
+        reply_to
+            .send(Reply::Frame(Message::Text(error.encode())))
+            .unwrap();
+        reply_to
+            .send(Reply::Disconnected {
+                turtle_id: turtle_id.clone(),
+                id: Some(0),
+            })
+            .unwrap();
+
+        let reply = next_reply(&turtle_id, &mut replies).await;
+        assert!(matches!(reply, Ok(TurtleMessage::CommandError { .. })));
+        let reply = next_reply(&turtle_id, &mut replies).await;
+        assert_eq!(
+            reply.err().unwrap(),
+            "turtle1 disconnected before answering"
+        );
+    }
+}
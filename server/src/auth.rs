@@ -0,0 +1,194 @@
+//! Tokens for turtles and operators. Turtles pass theirs as `?token=` next to
+//! their `turtle_id`, operators as `?token=` or an `Authorization: Bearer`
+//! header. A side without any tokens configured is left open.
+
+use axum::async_trait;
+use axum::extract::{FromRef, FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::Response;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::api;
+
+/// Turtles an operator token may see and command. Configured as a list of
+/// turtle ids, `["*"]` for all of them.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(from = "Vec<String>")]
+pub enum Scope {
+    All,
+    Turtles(HashSet<String>),
+}
+
+impl Scope {
+    pub fn allows(&self, turtle_id: &str) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Turtles(turtles) => turtles.contains(turtle_id),
+        }
+    }
+}
+
+impl From<Vec<String>> for Scope {
+    fn from(turtles: Vec<String>) -> Self {
+        if turtles.iter().any(|turtle| turtle == "*") {
+            Scope::All
+        } else {
+            Scope::Turtles(turtles.into_iter().collect())
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// Token any turtle may connect with.
+    pub turtle_secret: Option<String>,
+    /// Tokens for single turtles by turtle id, these take precedence over the shared secret.
+    pub turtle_tokens: HashMap<String, String>,
+    /// What each operator token gives access to.
+    pub operator_tokens: HashMap<String, Scope>,
+}
+
+impl Auth {
+    pub fn turtles_open(&self) -> bool {
+        self.turtle_secret.is_none() && self.turtle_tokens.is_empty()
+    }
+
+    pub fn operators_open(&self) -> bool {
+        self.operator_tokens.is_empty()
+    }
+
+    pub fn check_turtle(&self, turtle_id: &str, token: Option<&str>) -> bool {
+        if self.turtles_open() {
+            return true;
+        }
+        let Some(token) = token else {
+            return false;
+        };
+
+        match (self.turtle_tokens.get(turtle_id), &self.turtle_secret) {
+            (Some(expected), _) | (None, Some(expected)) => same_token(expected, token),
+            (None, None) => false,
+        }
+    }
+
+    pub fn operator_scope(&self, token: Option<&str>) -> Option<Scope> {
+        if self.operators_open() {
+            return Some(Scope::All);
+        }
+        let token = token?;
+
+        self.operator_tokens
+            .iter()
+            .find(|(expected, _)| same_token(expected, token))
+            .map(|(_, scope)| scope.clone())
+    }
+}
+
+/// Compares every byte of the SHA-256 digests, which are the same length
+/// whatever was sent, so the time taken gives away neither how much of a
+/// guess was right nor how long the token is.
+fn same_token(expected: &str, token: &str) -> bool {
+    Sha256::digest(expected)
+        .iter()
+        .zip(Sha256::digest(token).iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// An operator that presented a valid token, rejects the request with 401 otherwise.
+pub struct Operator(pub Scope);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Operator
+where
+    Arc<Auth>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let auth = Arc::<Auth>::from_ref(state);
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned);
+        let token = match bearer {
+            Some(token) => Some(token),
+            None => Query::<TokenQuery>::try_from_uri(&parts.uri)
+                .ok()
+                .and_then(|Query(query)| query.token),
+        };
+
+        let reason = match (auth.operator_scope(token.as_deref()), token) {
+            (Some(scope), _) => return Ok(Operator(scope)),
+            (None, Some(_)) => "bad token",
+            (None, None) => "no token",
+        };
+        tracing::warn!(
+            "rejected operator {} {}: {}",
+            parts.method,
+            parts.uri.path(),
+            reason
+        );
+        Err(api::error(StatusCode::UNAUTHORIZED, reason.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth {
+            turtle_secret: Some("shared".to_owned()),
+            turtle_tokens: HashMap::from([("miner".to_owned(), "miner-token".to_owned())]),
+            operator_tokens: HashMap::from([
+                ("admin".to_owned(), Scope::All),
+                (
+                    "helper".to_owned(),
+                    Scope::Turtles(HashSet::from(["miner".to_owned()])),
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn turtles_need_their_own_token_or_the_shared_one() {
+        let auth = auth();
+
+        assert!(auth.check_turtle("builder", Some("shared")));
+        assert!(!auth.check_turtle("builder", Some("miner-token")));
+        assert!(!auth.check_turtle("builder", None));
+        // A turtle with its own token can not fall back to the shared one
+        assert!(auth.check_turtle("miner", Some("miner-token")));
+        assert!(!auth.check_turtle("miner", Some("shared")));
+
+        assert!(Auth::default().check_turtle("builder", None));
+    }
+
+    #[test]
+    fn operator_tokens_are_scoped() {
+        let auth = auth();
+
+        assert_eq!(auth.operator_scope(Some("admin")), Some(Scope::All));
+        let helper = auth.operator_scope(Some("helper")).unwrap();
+        assert!(helper.allows("miner"));
+        assert!(!helper.allows("builder"));
+        assert_eq!(auth.operator_scope(Some("admi")), None);
+        assert_eq!(auth.operator_scope(None), None);
+
+        assert_eq!(Auth::default().operator_scope(None), Some(Scope::All));
+    }
+}
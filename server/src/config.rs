@@ -0,0 +1,167 @@
+//! Server settings from a TOML file, overridden by command line flags.
+//!
+//! ```toml
+//! bind = "0.0.0.0:1234"
+//! log = "info,server=debug"
+//!
+//! [auth]
+//! turtle_secret = "shared by every turtle"
+//! turtle_tokens = { miner = "only for the miner" }
+//! # Operator tokens and the turtles they may command, "*" for all of them
+//! operator_tokens = { admin = ["*"], helper = ["miner"] }
+//!
+//! [persistence]
+//! turtles = "turtles.json"
+//! ```
+
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::auth::{Auth, Scope};
+
+#[derive(Parser, Debug, Default)]
+#[command(about = "Relays commands from operators to ComputerCraft turtles")]
+pub struct Args {
+    /// TOML file to read settings from, the flags below take precedence.
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on.
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Log filter, like `info` or `warn,server=debug`.
+    #[arg(long, env = "RUST_LOG")]
+    pub log: Option<String>,
+    /// Token any turtle may connect with.
+    #[arg(long, env = "TURTLE_SECRET")]
+    pub turtle_secret: Option<String>,
+    /// Operator token with access to every turtle.
+    #[arg(long, env = "OPERATOR_TOKEN")]
+    pub operator_token: Option<String>,
+    /// JSON file where known turtles are kept between runs.
+    #[arg(long)]
+    pub turtles_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    /// An `EnvFilter` directive.
+    pub log: String,
+    pub auth: Auth,
+    pub persistence: Persistence,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Persistence {
+    /// Where the turtles seen so far are saved, nothing is kept when unset.
+    pub turtles: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 1234).into(),
+            log: "info".to_owned(),
+            auth: Auth::default(),
+            persistence: Persistence::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "{} is not valid: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the file `args` points at, if any, then applies the flags on top.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Read(path.clone(), err))?;
+                toml::from_str(&text).map_err(|err| ConfigError::Parse(path.clone(), err))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(bind) = args.bind {
+            config.bind = bind;
+        }
+        if let Some(log) = args.log {
+            config.log = log;
+        }
+        if let Some(secret) = args.turtle_secret {
+            config.auth.turtle_secret = Some(secret);
+        }
+        if let Some(token) = args.operator_token {
+            config.auth.operator_tokens.insert(token, Scope::All);
+        }
+        if let Some(path) = args.turtles_file {
+            config.persistence.turtles = Some(path);
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("server-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            bind = "127.0.0.1:4000"
+            log = "debug"
+
+            [auth]
+            turtle_secret = "from file"
+            operator_tokens = { helper = ["miner"], admin = ["*"] }
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(Args {
+            config: Some(path.clone()),
+            turtle_secret: Some("from flag".to_owned()),
+            turtles_file: Some("turtles.json".into()),
+            ..Args::default()
+        })
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.log, "debug");
+        assert_eq!(config.auth.turtle_secret.as_deref(), Some("from flag"));
+        assert_eq!(config.auth.operator_tokens["admin"], Scope::All);
+        assert!(config.auth.operator_tokens["helper"].allows("miner"));
+        assert!(!config.auth.operator_tokens["helper"].allows("builder"));
+        assert_eq!(config.persistence.turtles, Some("turtles.json".into()));
+    }
+
+    #[test]
+    fn typos_are_errors() {
+        assert!(toml::from_str::<Config>("bnid = \"0.0.0.0:1\"").is_err());
+    }
+}
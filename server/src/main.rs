@@ -1,37 +1,412 @@
+pub mod api;
+pub mod auth;
+pub mod config;
 pub mod run;
 pub mod turtle_manager;
 
+use clap::Parser;
+use config::{Args, Config};
 use run::run;
 
 #[tokio::main]
 pub async fn main() {
-    run().await;
+    let config = match Config::load(Args::parse()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    run(config).await;
 }
 
 #[cfg(test)]
 mod tests {
-    use super::run;
+    use super::auth::{Auth, Scope};
+    use super::config::Config;
+    use super::run::app;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use axum::Router;
+    use fake_turtle::FakeTurtle;
     use futures_util::sink::SinkExt;
     use futures_util::StreamExt;
-    #[tokio::test]
-    async fn test_async() {
-        tokio::spawn(run());
-        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-        let (mut turtle1, _) =
-            tokio_tungstenite::connect_async("ws://127.0.0.1:1234/ws?turtle_id=turtle1")
+    use protocol::{OperatorMessage, Routed, TurtleMessage, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+    use tower::ServiceExt;
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn serve(app: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn connect_operator(addr: SocketAddr) -> Socket {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/operator"))
+            .await
+            .expect("Failed to connect");
+        socket
+    }
+
+    fn connect_fake_turtle(addr: SocketAddr, turtle_id: &str) -> Arc<Mutex<FakeTurtle>> {
+        let turtle = Arc::new(Mutex::new(FakeTurtle::default()));
+        let url = format!("ws://{addr}/ws?turtle_id={turtle_id}");
+        let shared = turtle.clone();
+        tokio::spawn(async move { fake_turtle::run(&url, shared, Duration::from_secs(60)).await });
+        turtle
+    }
+
+    async fn send_command(operator: &mut Socket, turtle: &str, id: u32, code: &str) {
+        let command = Routed {
+            turtle: turtle.to_string(),
+            message: OperatorMessage::Command {
+                id,
+                code: code.to_string(),
+            },
+        };
+        operator
+            .send(Message::Text(command.encode()))
+            .await
+            .unwrap();
+    }
+
+    /// Skips turtle telemetry until the next frame matching `filter`.
+    async fn next_frame(
+        operator: &mut Socket,
+        filter: impl Fn(&TurtleMessage) -> bool,
+    ) -> Routed<TurtleMessage> {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(5), operator.next())
                 .await
-                .expect("Failed to connect");
-        let (mut turtle2, _) =
-            tokio_tungstenite::connect_async("ws://127.0.0.1:1234/ws?turtle_id=turtle2")
+                .expect("timed out")
+                .unwrap()
+                .unwrap();
+            let routed = Routed::decode(frame.to_text().unwrap()).unwrap();
+            if filter(&routed.message) {
+                return routed;
+            }
+        }
+    }
+
+    /// The next command, cancel or clear the relay sent to a turtle.
+    async fn next_for_turtle(turtle: &mut Socket) -> OperatorMessage {
+        loop {
+            if let Message::Text(text) = turtle.next().await.unwrap().unwrap() {
+                return OperatorMessage::decode(&text).unwrap();
+            }
+        }
+    }
+
+    fn is_reply(message: &TurtleMessage) -> bool {
+        matches!(
+            message,
+            TurtleMessage::CommandResult { .. } | TurtleMessage::CommandError { .. }
+        )
+    }
+
+    #[tokio::test]
+    async fn replies_go_back_to_the_operator_that_asked() {
+        let addr = serve(app(Config::default())).await;
+        let mut alice = connect_operator(addr).await;
+        let mut bob = connect_operator(addr).await;
+
+        let turtle = connect_fake_turtle(addr, "turtle1");
+        // The turtle says hello to every operator once it is registered
+        let hello = next_frame(&mut alice, |message| {
+            matches!(message, TurtleMessage::Status { .. })
+        })
+        .await;
+        assert_eq!(hello.turtle, "turtle1");
+
+        // Both use id 1, the relay keeps them apart
+        send_command(&mut alice, "turtle1", 1, "return turtle.forward()").await;
+        send_command(&mut bob, "turtle1", 1, "return turtle.inspectDown()").await;
+
+        let reply = next_frame(&mut alice, is_reply).await;
+        assert_eq!(reply.turtle, "turtle1");
+        assert_eq!(
+            reply.message,
+            TurtleMessage::CommandResult {
+                id: Some(1),
+                values: vec![Value::Bool(true)]
+            }
+        );
+        let TurtleMessage::CommandResult { id, values } =
+            next_frame(&mut bob, is_reply).await.message
+        else {
+            panic!("inspectDown failed");
+        };
+        assert_eq!(id, Some(1));
+        assert_eq!(values[1]["name"], "minecraft:grass_block");
+
+        assert_eq!(turtle.lock().unwrap().position, (0, 0, -1));
+    }
+
+    #[tokio::test]
+    async fn operators_see_turtles_come_and_go() {
+        let addr = serve(app(Config::default())).await;
+        let mut operator = connect_operator(addr).await;
+        let is_presence =
+            |message: &TurtleMessage| matches!(message, TurtleMessage::Presence { .. });
+
+        let url = format!("ws://{addr}/ws?turtle_id=turtle1");
+        let (mut turtle, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let online = next_frame(&mut operator, is_presence).await;
+        assert_eq!(online.turtle, "turtle1");
+        assert!(matches!(
+            online.message,
+            TurtleMessage::Presence { online: true, .. }
+        ));
+
+        turtle.close(None).await.unwrap();
+        let offline = next_frame(&mut operator, is_presence).await;
+        assert!(matches!(
+            offline.message,
+            TurtleMessage::Presence { online: false, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn odd_frames_and_missing_turtles_are_reported_not_fatal() {
+        let addr = serve(app(Config::default())).await;
+        let mut operator = connect_operator(addr).await;
+
+        send_command(&mut operator, "nobody", 7, "return turtle.forward()").await;
+        let reply = next_frame(&mut operator, is_reply).await;
+        assert_eq!(reply.turtle, "nobody");
+        assert!(matches!(
+            reply.message,
+            TurtleMessage::CommandError { id: Some(7), .. }
+        ));
+
+        let url = format!("ws://{addr}/ws?turtle_id=turtle1");
+        let (mut turtle, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        turtle.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+        turtle.send(Message::Ping(vec![])).await.unwrap();
+        operator.send(Message::Binary(vec![4])).await.unwrap();
+
+        // Still connected, the command reaches the turtle
+        send_command(&mut operator, "turtle1", 8, "return turtle.forward()").await;
+        assert!(matches!(
+            next_for_turtle(&mut turtle).await,
+            OperatorMessage::Command { .. }
+        ));
+
+        // Leaving without answering fails the command instead of leaving it hanging
+        turtle.close(None).await.unwrap();
+        let reply = next_frame(&mut operator, is_reply).await;
+        assert!(matches!(
+            reply.message,
+            TurtleMessage::CommandError { id: Some(8), .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn clear_only_cancels_the_operators_own_queued_commands() {
+        let addr = serve(app(Config::default())).await;
+        let mut alice = connect_operator(addr).await;
+        let mut bob = connect_operator(addr).await;
+        let url = format!("ws://{addr}/ws?turtle_id=turtle1");
+        let (mut turtle, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        next_frame(&mut alice, |message| {
+            matches!(message, TurtleMessage::Presence { .. })
+        })
+        .await;
+        send_command(&mut alice, "turtle1", 1, "return turtle.dig()").await;
+        let running = next_for_turtle(&mut turtle).await;
+        send_command(&mut alice, "turtle1", 2, "return turtle.forward()").await;
+        let queued = next_for_turtle(&mut turtle).await;
+        send_command(&mut bob, "turtle1", 1, "return turtle.up()").await;
+        let bobs = next_for_turtle(&mut turtle).await;
+        let id = |message: &OperatorMessage| match message {
+            OperatorMessage::Command { id, .. } => *id,
+            _ => panic!("expected a command, got {message:?}"),
+        };
+        let queue = TurtleMessage::Queue {
+            running: Some(id(&running)),
+            queued: vec![id(&queued), id(&bobs)],
+        };
+        turtle.send(Message::Text(queue.encode())).await.unwrap();
+        // Alice hears about the queue once the relay has seen it
+        next_frame(&mut alice, |message| {
+            matches!(message, TurtleMessage::Queue { .. })
+        })
+        .await;
+
+        let clear = Routed {
+            turtle: "turtle1".to_string(),
+            message: OperatorMessage::Clear,
+        };
+        alice.send(Message::Text(clear.encode())).await.unwrap();
+        assert_eq!(
+            next_for_turtle(&mut turtle).await,
+            OperatorMessage::Cancel { id: id(&queued) }
+        );
+        // Nothing else was cancelled, the next frame is Bob's next command
+        send_command(&mut bob, "turtle1", 2, "return turtle.down()").await;
+        assert!(matches!(
+            next_for_turtle(&mut turtle).await,
+            OperatorMessage::Command { .. }
+        ));
+    }
+
+    async fn request(app: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rest_api_lists_and_commands_turtles() {
+        let app = app(Config::default());
+        let addr = serve(app.clone()).await;
+        let mut operator = connect_operator(addr).await;
+        let turtle = connect_fake_turtle(addr, "turtle1");
+        next_frame(&mut operator, |message| {
+            matches!(message, TurtleMessage::Pose { .. })
+        })
+        .await;
+
+        // The registry handled the pose before the operator could see it
+        let (status, turtles) = request(&app, "GET", "/turtles", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(turtles[0]["id"], "turtle1");
+        assert_eq!(turtles[0]["online"], true);
+        assert_eq!(turtles[0]["position"], serde_json::json!([0, 0, 0]));
+
+        let (status, reply) = request(
+            &app,
+            "POST",
+            "/turtles/turtle1/command",
+            r#"{"code":"return turtle.up()"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(reply["type"], "command_result");
+        assert_eq!(reply["values"], serde_json::json!([true]));
+        assert_eq!(turtle.lock().unwrap().position, (0, 1, 0));
+
+        let (status, _) = request(&app, "GET", "/turtles/turtle2", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request(
+            &app,
+            "POST",
+            "/turtles/turtle2/command",
+            r#"{"code":"return turtle.up()"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rest_commands_fail_when_the_turtle_leaves() {
+        let app = app(Config::default());
+        let addr = serve(app.clone()).await;
+        let mut operator = connect_operator(addr).await;
+        let url = format!("ws://{addr}/ws?turtle_id=turtle1");
+        let (mut turtle, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        next_frame(&mut operator, |message| {
+            matches!(message, TurtleMessage::Presence { .. })
+        })
+        .await;
+
+        let command = tokio::spawn({
+            let app = app.clone();
+            async move {
+                request(
+                    &app,
+                    "POST",
+                    "/turtles/turtle1/command",
+                    r#"{"code":"return turtle.up()"}"#,
+                )
                 .await
-                .expect("Failed to connect");
-        turtle1
-            .send(tokio_tungstenite::tungstenite::Message::Text(
-                "turtle2".to_string(),
-            ))
+            }
+        });
+        // Leave as soon as the command arrives, without answering it
+        while !matches!(turtle.next().await.unwrap().unwrap(), Message::Text(_)) {}
+        turtle.close(None).await.unwrap();
+
+        let (status, body) = tokio::time::timeout(Duration::from_secs(5), command)
             .await
+            .expect("timed out")
             .unwrap();
-        let a = turtle2.next().await.unwrap().unwrap();
-        assert_eq!(a, "turtle1 sendte deg en melding!!!".into())
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["error"], "turtle1 disconnected before answering");
+    }
+
+    #[tokio::test]
+    async fn tokens_keep_strangers_out() {
+        let auth = Auth {
+            turtle_secret: Some("turtles".to_owned()),
+            operator_tokens: HashMap::from([(
+                "helper".to_owned(),
+                Scope::Turtles(HashSet::from(["turtle1".to_owned()])),
+            )]),
+            ..Auth::default()
+        };
+        let app = app(Config {
+            auth,
+            ..Config::default()
+        });
+        let addr = serve(app.clone()).await;
+
+        let rejected =
+            |url: String| async move { tokio_tungstenite::connect_async(url).await.is_err() };
+        assert!(rejected(format!("ws://{addr}/ws?turtle_id=turtle1")).await);
+        assert!(rejected(format!("ws://{addr}/ws?turtle_id=turtle1&token=nope")).await);
+        assert!(rejected(format!("ws://{addr}/operator")).await);
+        let (status, _) = request(&app, "GET", "/turtles", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (mut operator, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/operator?token=helper"))
+                .await
+                .unwrap();
+        connect_fake_turtle(addr, "turtle2&token=turtles");
+        connect_fake_turtle(addr, "turtle1&token=turtles");
+
+        // turtle2 connected first, but the operator only hears about turtle1
+        let online = next_frame(&mut operator, |message| {
+            matches!(message, TurtleMessage::Presence { .. })
+        })
+        .await;
+        assert_eq!(online.turtle, "turtle1");
+
+        send_command(&mut operator, "turtle2", 1, "return turtle.forward()").await;
+        let reply = next_frame(&mut operator, is_reply).await;
+        assert_eq!(reply.turtle, "turtle2");
+        assert!(matches!(
+            reply.message,
+            TurtleMessage::CommandError { id: Some(1), .. }
+        ));
+
+        let (status, _) = request(
+            &app,
+            "POST",
+            "/turtles/turtle2/command?token=helper",
+            r#"{"code":"return turtle.up()"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
     }
 }
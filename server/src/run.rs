@@ -1,37 +1,86 @@
 use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{Query, State, WebSocketUpgrade};
-use axum::response::IntoResponse;
-use axum::routing::get;
+use axum::extract::{FromRef, Query, State, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use axum::Router;
+use protocol::{CommandId, OperatorMessage, Routed, TurtleMessage};
 use serde::Deserialize;
 use std::collections::HashMap;
-use tokio::sync::{mpsc, oneshot};
-use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-use crate::turtle_manager::{turtle_manager, TurtleId, TurtleRegistry};
+use crate::api;
+use crate::auth::{Auth, Operator, Scope};
+use crate::config::Config;
+use crate::turtle_manager::{
+    Presence, PresenceEvent, Reply, TurtleId, TurtleRegistry, TurtleRequest,
+};
 
 #[derive(Deserialize)]
 struct Pagination {
     turtle_id: String,
+    token: Option<String>,
 }
-impl Into<TurtleId> for Pagination {
-    fn into(self) -> TurtleId {
-        TurtleId(self.turtle_id)
+impl From<Pagination> for TurtleId {
+    fn from(pagination: Pagination) -> Self {
+        TurtleId(pagination.turtle_id)
     }
 }
 
-pub async fn run() {
-    let subscriber = FmtSubscriber::builder()
-        // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
-        .with_max_level(Level::TRACE)
-        // completes the builder.
-        .finish();
-
+pub async fn run(config: Config) {
+    let filter = EnvFilter::try_new(&config.log).unwrap_or_else(|err| {
+        eprintln!("Bad log filter {:?}, using info: {}", config.log, err);
+        EnvFilter::new("info")
+    });
+    let subscriber = FmtSubscriber::builder().with_env_filter(filter).finish();
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-    let turtle_registry = TurtleRegistry::start();
-    let app = Router::new()
+
+    if config.auth.turtles_open() {
+        tracing::warn!("no turtle tokens are configured, any turtle can connect");
+    }
+    if config.auth.operators_open() {
+        tracing::warn!("no operator tokens are configured, anyone can command the turtles");
+    }
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("could not listen on {}: {}", config.bind, err);
+            return;
+        }
+    };
+    tracing::info!("listening on {}", listener.local_addr().unwrap());
+    axum::serve(listener, app(config)).await.unwrap();
+}
+
+/// What the handlers share, each extracts the part it needs.
+#[derive(Clone)]
+pub(crate) struct AppState {
+    turtles: TurtleRegistry,
+    auth: Arc<Auth>,
+}
+
+impl FromRef<AppState> for TurtleRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.turtles.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Auth> {
+    fn from_ref(state: &AppState) -> Self {
+        state.auth.clone()
+    }
+}
+
+/// Turtles connect to `/ws?turtle_id=<id>&token=<token>`, operators to
+/// `/operator` or the REST endpoints under `/turtles`.
+pub fn app(config: Config) -> Router {
+    let state = AppState {
+        turtles: TurtleRegistry::start(config.persistence.turtles),
+        auth: Arc::new(config.auth),
+    };
+    Router::new()
         .route(
             "/hw",
             get(|| async {
@@ -40,48 +89,405 @@ pub async fn run() {
             }),
         )
         .route("/ws", get(websocket_handler))
-        .with_state(turtle_registry);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:1234").await.unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+        .route("/operator", get(operator_websocket_handler))
+        .route("/turtles", get(api::list_turtles))
+        .route("/turtles/:id", get(api::get_turtle))
+        .route("/turtles/:id/command", post(api::command_turtle))
+        .with_state(state)
 }
 
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     Query(pagination): Query<Pagination>,
     State(app_state): State<TurtleRegistry>,
-) -> impl IntoResponse {
+    State(auth): State<Arc<Auth>>,
+) -> Response {
+    if !auth.check_turtle(&pagination.turtle_id, pagination.token.as_deref()) {
+        tracing::warn!(
+            "rejected {}: {}",
+            pagination.turtle_id,
+            if pagination.token.is_some() {
+                "bad token"
+            } else {
+                "no token"
+            }
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
     ws.on_upgrade(|socket| websocket(socket, app_state, pagination.into()))
 }
 
+async fn operator_websocket_handler(
+    ws: WebSocketUpgrade,
+    Operator(scope): Operator,
+    State(app_state): State<TurtleRegistry>,
+) -> impl IntoResponse {
+    ws.on_upgrade(|socket| operator_websocket(socket, app_state, scope))
+}
+
 async fn websocket(mut socket: WebSocket, app_state: TurtleRegistry, turtle_id: TurtleId) {
     let (sender, mut receiver) = mpsc::unbounded_channel();
-    app_state.register_turtle.send((turtle_id.clone(), sender));
+    let connection = app_state.register(turtle_id.clone(), sender);
     tracing::debug!("{} opened socket", turtle_id.0);
-    loop {
+
+    // Operators pick their own command ids, so commands get a fresh id on the
+    // way to the turtle and the operator's id back on the reply
+    let mut next_id: CommandId = 0;
+    let mut pending = Pending::new();
+    // As of the turtle's latest queue report, under its own id
+    let mut running = None;
+    'relay: loop {
         tokio::select! {
             socket_option = socket.recv() => match socket_option {
-                Some(result) => match result {
-                    Ok(msg) => {
-                        let Message::Text(msg) = msg else {
-                            panic!();
-                        };
-                        tracing::debug!("{} got socket message: {:?}", turtle_id.0, msg);
-                        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
-                        app_state.get_turtle.send((TurtleId(msg), oneshot_sender));
-                        let a = oneshot_receiver.await.ok().flatten().unwrap();
-                        let tutel = turtle_id.0.clone();
-                        a.send(Message::Text(format!("{tutel} sendte deg en melding!!!")));
-                    },
-                    Err(err) => {socket.close(); return},
+                Some(Ok(Message::Text(msg))) => {
+                    tracing::debug!("{} got socket message: {:?}", turtle_id.0, msg);
+                    route_turtle_message(&turtle_id, &msg, &mut pending, &mut running, &app_state);
                 },
-                None => todo!(),
+                Some(Ok(Message::Binary(data))) => {
+                    tracing::warn!("{} sent {} bytes of binary data, ignoring it", turtle_id.0, data.len());
+                },
+                // axum answers pings on its own
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => (),
+                Some(Ok(Message::Close(frame))) => {
+                    tracing::debug!("{} closed the connection: {:?}", turtle_id.0, frame);
+                    break;
+                },
+                Some(Err(err)) => {
+                    tracing::warn!("{} socket failed: {}", turtle_id.0, err);
+                    break;
+                },
+                None => break,
             },
             mpsc_option = receiver.recv() => match mpsc_option {
-                Some(msg) => { tracing::debug!("{}, got mpsc message: {:?}", turtle_id.0, msg); socket.send(msg).await; },
-                None => panic!()
+                Some(request) => {
+                    tracing::debug!("{}, got {:?}", turtle_id.0, request.message);
+                    for message in to_turtle_ids(&turtle_id, request, &mut next_id, &mut pending, running) {
+                        if socket.send(Message::Text(message.encode())).await.is_err() {
+                            break 'relay;
+                        }
+                    }
+                },
+                None => {
+                    tracing::debug!("{} connected again, closing the old socket", turtle_id.0);
+                    break;
+                }
             },
-            else => break,
+        }
+    }
+
+    app_state.unregister(turtle_id.clone(), connection);
+    for (operator_id, reply_to) in pending.into_values() {
+        let _ = reply_to.send(Reply::Disconnected {
+            turtle_id: turtle_id.clone(),
+            id: Some(operator_id),
+        });
+    }
+    // Requests that were still on their way to the socket get the same answer
+    receiver.close();
+    while let Ok(request) = receiver.try_recv() {
+        let _ = request.reply_to.send(Reply::Disconnected {
+            turtle_id: turtle_id.clone(),
+            id: operator_id(&request.message),
+        });
+    }
+    let _ = socket.close().await;
+    tracing::debug!("{} closed socket", turtle_id.0);
+}
+
+/// The error commands get when their turtle goes away without answering.
+pub(crate) fn disconnected(turtle_id: &TurtleId) -> String {
+    format!("{} disconnected before answering", turtle_id.0)
+}
+
+/// The id the operator picked for `message`, if it has one.
+fn operator_id(message: &OperatorMessage) -> Option<CommandId> {
+    match message {
+        OperatorMessage::Command { id, .. } | OperatorMessage::Cancel { id } => Some(*id),
+        OperatorMessage::Clear => None,
+    }
+}
+
+/// A `command_error` frame from the server on behalf of `turtle`.
+fn command_error(turtle: &str, id: Option<CommandId>, error: String) -> Message {
+    let message = Routed {
+        turtle: turtle.to_string(),
+        message: TurtleMessage::CommandError { id, error },
+    };
+
+    Message::Text(message.encode())
+}
+
+type Pending = HashMap<CommandId, (CommandId, mpsc::UnboundedSender<Reply>)>;
+
+/// Swaps the operator's command ids for ones unique on the turtle, returning
+/// the frames to send it.
+fn to_turtle_ids(
+    turtle_id: &TurtleId,
+    request: TurtleRequest,
+    next_id: &mut CommandId,
+    pending: &mut Pending,
+    running: Option<CommandId>,
+) -> Vec<OperatorMessage> {
+    match request.message {
+        OperatorMessage::Command { id, code } => {
+            *next_id = next_id.wrapping_add(1);
+            pending.insert(*next_id, (id, request.reply_to));
+            vec![OperatorMessage::Command { id: *next_id, code }]
+        }
+        OperatorMessage::Cancel { id } => {
+            let turtle_side = pending
+                .iter()
+                .find_map(|(turtle_side, (operator_id, reply_to))| {
+                    (*operator_id == id && reply_to.same_channel(&request.reply_to))
+                        .then_some(*turtle_side)
+                });
+            if turtle_side.is_none() {
+                let error = format!("no command {} is waiting on {}", id, turtle_id.0);
+                let _ = request.reply_to.send(Reply::Frame(command_error(
+                    &turtle_id.0,
+                    Some(id),
+                    error,
+                )));
+            }
+            turtle_side
+                .map(|id| OperatorMessage::Cancel { id })
+                .into_iter()
+                .collect()
+        }
+        // The turtle's own clear would drop every operator's commands, so
+        // only this operator's are cancelled, all but the one running
+        OperatorMessage::Clear => {
+            let mut queued: Vec<CommandId> = pending
+                .iter()
+                .filter(|(turtle_side, (_, reply_to))| {
+                    Some(**turtle_side) != running && reply_to.same_channel(&request.reply_to)
+                })
+                .map(|(turtle_side, _)| *turtle_side)
+                .collect();
+            queued.sort_unstable();
+
+            queued
+                .into_iter()
+                .map(|id| OperatorMessage::Cancel { id })
+                .collect()
+        }
+    }
+}
+
+/// Gives every operator with commands on the turtle its share of the queue,
+/// under its own ids.
+fn forward_queue(
+    turtle_id: &TurtleId,
+    running: Option<CommandId>,
+    queued: &[CommandId],
+    pending: &Pending,
+) {
+    let mut operators: Vec<&mpsc::UnboundedSender<Reply>> = vec![];
+    for (_, reply_to) in pending.values() {
+        if !operators
+            .iter()
+            .any(|operator| operator.same_channel(reply_to))
+        {
+            operators.push(reply_to);
+        }
+    }
+
+    for operator in operators {
+        let own = |id: &CommandId| {
+            pending
+                .get(id)
+                .filter(|(_, reply_to)| reply_to.same_channel(operator))
+                .map(|(operator_id, _)| *operator_id)
+        };
+        let queue = Routed {
+            turtle: turtle_id.0.clone(),
+            message: TurtleMessage::Queue {
+                running: running.as_ref().and_then(own),
+                queued: queued.iter().filter_map(own).collect(),
+            },
+        };
+        let _ = operator.send(Reply::Frame(Message::Text(queue.encode())));
+    }
+}
+
+/// Sends replies to the operator that issued the command, everything else to all operators.
+fn route_turtle_message(
+    turtle_id: &TurtleId,
+    msg: &str,
+    pending: &mut Pending,
+    running: &mut Option<CommandId>,
+    app_state: &TurtleRegistry,
+) {
+    let mut message = match TurtleMessage::decode(msg) {
+        Ok(message) => message,
+        Err(err) => {
+            tracing::warn!("{} sent a frame that does not decode: {}", turtle_id.0, err);
+            return;
+        }
+    };
+
+    let update = match message {
+        TurtleMessage::Status { .. } | TurtleMessage::Pose { .. } => Some(message.clone()),
+        _ => None,
+    };
+    app_state.seen(turtle_id.clone(), update);
+
+    if let TurtleMessage::Queue {
+        running: now_running,
+        queued,
+    } = &message
+    {
+        *running = *now_running;
+        forward_queue(turtle_id, *running, queued, pending);
+        return;
+    }
+
+    let command_id = match &mut message {
+        TurtleMessage::CommandResult { id, .. } | TurtleMessage::CommandError { id, .. } => {
+            id.as_mut()
+        }
+        _ => None,
+    };
+    match command_id {
+        Some(id) => {
+            let Some((operator_id, reply_to)) = pending.remove(id) else {
+                tracing::warn!("{} replied to unknown command {}", turtle_id.0, id);
+                return;
+            };
+            *id = operator_id;
+            let reply = Routed {
+                turtle: turtle_id.0.clone(),
+                message,
+            };
+            let _ = reply_to.send(Reply::Frame(Message::Text(reply.encode())));
+        }
+        None => {
+            let telemetry = Routed {
+                turtle: turtle_id.0.clone(),
+                message,
+            };
+            // Nobody listening is fine
+            let _ = app_state
+                .telemetry
+                .send((turtle_id.clone(), telemetry.encode()));
+        }
+    }
+}
+
+async fn operator_websocket(mut socket: WebSocket, app_state: TurtleRegistry, scope: Scope) {
+    let (reply_to, mut replies) = mpsc::unbounded_channel();
+    let mut telemetry = app_state.telemetry.subscribe();
+    let mut presence = app_state.presence.subscribe();
+    tracing::debug!("operator opened socket");
+
+    loop {
+        let msg = tokio::select! {
+            socket_option = socket.recv() => match socket_option {
+                Some(Ok(Message::Text(msg))) => {
+                    tracing::debug!("operator got socket message: {:?}", msg);
+                    forward_command(&msg, &reply_to, &app_state, &scope).await;
+                    continue;
+                },
+                Some(Ok(Message::Binary(data))) => {
+                    tracing::warn!("operator sent {} bytes of binary data, ignoring it", data.len());
+                    continue;
+                },
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Err(err)) => {
+                    tracing::warn!("operator socket failed: {}", err);
+                    break;
+                },
+            },
+            Some(reply) = replies.recv() => match reply {
+                Reply::Frame(frame) => frame,
+                Reply::Disconnected { turtle_id, id } => {
+                    command_error(&turtle_id.0, id, disconnected(&turtle_id))
+                },
+            },
+            telemetry_result = telemetry.recv() => match telemetry_result {
+                Ok((turtle_id, frame)) if scope.allows(&turtle_id.0) => Message::Text(frame),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("operator fell behind, skipped {} turtle messages", skipped);
+                    continue;
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            presence_result = presence.recv() => match presence_result {
+                Ok(event) if scope.allows(&event.turtle_id.0) => Message::Text(presence_frame(event)),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("operator fell behind, skipped {} presence changes", skipped);
+                    continue;
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+
+        if socket.send(msg).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = socket.close().await;
+    tracing::debug!("operator closed socket");
+}
+
+fn presence_frame(event: PresenceEvent) -> String {
+    let message = TurtleMessage::Presence {
+        online: event.presence == Presence::Online,
+        last_seen: event.last_seen,
+    };
+
+    Routed {
+        turtle: event.turtle_id.0,
+        message,
+    }
+    .encode()
+}
+
+async fn forward_command(
+    msg: &str,
+    reply_to: &mpsc::UnboundedSender<Reply>,
+    app_state: &TurtleRegistry,
+    scope: &Scope,
+) {
+    let Routed { turtle, message } = match Routed::<OperatorMessage>::decode(msg) {
+        Ok(routed) => routed,
+        Err(err) => {
+            tracing::warn!("operator sent a frame that does not decode: {}", err);
+            return;
+        }
+    };
+    let id = operator_id(&message);
+
+    if !scope.allows(&turtle) {
+        tracing::warn!(
+            "rejected operator {:?} to {}: out of scope",
+            message,
+            turtle
+        );
+        let error = format!("not allowed to command {}", turtle);
+        let _ = reply_to.send(Reply::Frame(command_error(&turtle, id, error)));
+        return;
+    }
+
+    let request = TurtleRequest {
+        message,
+        reply_to: reply_to.clone(),
+    };
+    match app_state.get(TurtleId(turtle.clone())).await {
+        Some(sender) if sender.send(request).is_ok() => (),
+        _ => {
+            tracing::warn!(
+                "operator sent {:?} to {}, which is not connected",
+                id,
+                turtle
+            );
+            let error = format!("{} is not connected", turtle);
+            let _ = reply_to.send(Reply::Frame(command_error(&turtle, id, error)));
         }
     }
 }
@@ -1,48 +1,431 @@
 use axum::extract::ws::Message;
+use protocol::{CommandId, Heading, OperatorMessage, PositionSource, TurtleMessage};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::future::Future;
-use tokio::sync::{mpsc, oneshot};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
-#[derive(Eq, PartialEq, Hash, Clone)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
 pub(crate) struct TurtleId(pub String);
 
-pub(crate) async fn turtle_manager(
-    mut turtle_registry_channel: mpsc::UnboundedReceiver<(
-        TurtleId,
-        mpsc::UnboundedSender<Message>,
-    )>,
-    mut turtle_request_channel: mpsc::UnboundedReceiver<(
+/// Tells apart two sockets that connected with the same [`TurtleId`].
+pub(crate) type ConnectionId = u64;
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(0);
+
+/// A message from an operator, for the turtle's socket task to forward.
+pub(crate) struct TurtleRequest {
+    /// Carries the operator's command ids, which are swapped for ids unique
+    /// on the turtle on the way there and put back on its replies.
+    pub message: OperatorMessage,
+    /// The operator's socket, where the reply is routed to.
+    pub reply_to: mpsc::UnboundedSender<Reply>,
+}
+
+/// What the turtle's socket task sends back to an operator.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    /// A frame to pass on as it is.
+    Frame(Message),
+    /// The turtle went away before answering the operator's command `id`.
+    Disconnected {
+        turtle_id: TurtleId,
+        id: Option<CommandId>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Presence {
+    Online,
+    Offline,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PresenceEvent {
+    pub turtle_id: TurtleId,
+    pub presence: Presence,
+    /// Unix seconds the turtle last sent anything, or connected if it has been quiet.
+    pub last_seen: u64,
+}
+
+/// What the server knows about a turtle that connected since it started, or
+/// before that if known turtles are saved.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct TurtleInfo {
+    pub id: String,
+    pub online: bool,
+    pub last_seen: u64,
+    /// From the turtle's status message.
+    pub computer_id: Option<u32>,
+    pub label: Option<String>,
+    /// From the turtle's latest pose message.
+    pub position: Option<[i32; 3]>,
+    pub heading: Option<Heading>,
+    pub source: Option<PositionSource>,
+}
+
+impl TurtleInfo {
+    fn new(turtle_id: &TurtleId) -> Self {
+        Self {
+            id: turtle_id.0.clone(),
+            online: false,
+            last_seen: unix_now(),
+            computer_id: None,
+            label: None,
+            position: None,
+            heading: None,
+            source: None,
+        }
+    }
+
+    fn update(&mut self, message: TurtleMessage) {
+        match message {
+            TurtleMessage::Status { id, label } => {
+                self.computer_id = id;
+                self.label = label;
+            }
+            TurtleMessage::Pose {
+                x,
+                y,
+                z,
+                heading,
+                source,
+            } => {
+                self.position = Some([x, y, z]);
+                self.heading = Some(heading);
+                self.source = Some(source);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Turtles saved to `path` by an earlier run, all offline until they connect again.
+fn load_turtles(path: &Path) -> HashMap<TurtleId, TurtleInfo> {
+    let turtles: Vec<TurtleInfo> = match std::fs::read(path) {
+        Ok(json) => match serde_json::from_slice(&json) {
+            Ok(turtles) => turtles,
+            Err(err) => {
+                tracing::error!(
+                    "ignoring {}, it is not a turtle list: {}",
+                    path.display(),
+                    err
+                );
+                return HashMap::new();
+            }
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            tracing::error!("could not read {}: {}", path.display(), err);
+            return HashMap::new();
+        }
+    };
+    tracing::info!("loaded {} turtles from {}", turtles.len(), path.display());
+
+    turtles
+        .into_iter()
+        .map(|info| {
+            (
+                TurtleId(info.id.clone()),
+                TurtleInfo {
+                    online: false,
+                    ..info
+                },
+            )
+        })
+        .collect()
+}
+
+/// Writes a temporary file next to `path` and renames it into place, so a
+/// crash halfway leaves the previous save intact.
+async fn save_turtles(path: &Path, turtle_info: &HashMap<TurtleId, TurtleInfo>) {
+    let mut turtles: Vec<&TurtleInfo> = turtle_info.values().collect();
+    turtles.sort_by(|a, b| a.id.cmp(&b.id));
+    let json = serde_json::to_vec_pretty(&turtles).expect("turtle info is always valid json");
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let result = match tokio::fs::write(&temporary, json).await {
+        Ok(()) => tokio::fs::rename(&temporary, path).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        tracing::error!("could not save turtles to {}: {}", path.display(), err);
+    }
+}
+
+/// Everything the manager task is asked to do. It all goes through one
+/// channel so a turtle's updates are handled in the order they were sent,
+/// never before the turtle is registered or after it is gone.
+pub(crate) enum RegistryRequest {
+    /// Makes the sender the way to reach the turtle.
+    Register(TurtleId, ConnectionId, mpsc::UnboundedSender<TurtleRequest>),
+    Unregister(TurtleId, ConnectionId),
+    /// Marks the turtle as heard from just now, along with its status or
+    /// pose if that is what it sent.
+    Seen(TurtleId, Option<TurtleMessage>),
+    Get(
         TurtleId,
-        oneshot::Sender<Option<mpsc::UnboundedSender<Message>>>,
-    )>,
+        oneshot::Sender<Option<mpsc::UnboundedSender<TurtleRequest>>>,
+    ),
+    List(oneshot::Sender<Vec<TurtleInfo>>),
+}
+
+pub(crate) async fn turtle_manager(
+    mut requests: mpsc::UnboundedReceiver<RegistryRequest>,
+    presence: broadcast::Sender<PresenceEvent>,
+    store: Option<PathBuf>,
 ) {
-    let mut turtle_registry: HashMap<TurtleId, mpsc::UnboundedSender<Message>> = HashMap::new();
-    loop {
-        tokio::select! {
-            Some((turtle_id, sender)) = turtle_registry_channel.recv() => {turtle_registry.insert(turtle_id.clone(), sender.clone());},
-            Some((turtle_id, sender)) = turtle_request_channel.recv() => {sender.send(turtle_registry.get(&turtle_id).cloned()).unwrap()}
+    let mut turtle_registry: HashMap<
+        TurtleId,
+        (ConnectionId, mpsc::UnboundedSender<TurtleRequest>),
+    > = HashMap::new();
+    let mut turtle_info: HashMap<TurtleId, TurtleInfo> =
+        store.as_deref().map(load_turtles).unwrap_or_default();
+    let announce = |info: &TurtleInfo| {
+        let state = if info.online {
+            Presence::Online
+        } else {
+            Presence::Offline
+        };
+        tracing::info!("{} is {:?}", info.id, state);
+        // Nobody subscribed is fine
+        let _ = presence.send(PresenceEvent {
+            turtle_id: TurtleId(info.id.clone()),
+            presence: state,
+            last_seen: info.last_seen,
+        });
+    };
+
+    // Ends once every registry handle is gone, the server is shutting down
+    while let Some(request) = requests.recv().await {
+        // Set when a turtle comes or goes, which is also when its last pose is worth keeping
+        let mut save = false;
+        match request {
+            RegistryRequest::Register(turtle_id, connection, sender) => {
+                let info = turtle_info
+                    .entry(turtle_id.clone())
+                    .or_insert_with(|| TurtleInfo::new(&turtle_id));
+                info.online = true;
+                info.last_seen = unix_now();
+                // Dropping the old sender tells the old socket task to close
+                match turtle_registry.insert(turtle_id.clone(), (connection, sender)) {
+                    Some(_) => {
+                        tracing::info!("{} reconnected, replacing its old socket", turtle_id.0)
+                    }
+                    None => {
+                        announce(info);
+                        save = true;
+                    }
+                }
+            }
+            RegistryRequest::Unregister(turtle_id, connection) => {
+                // A replaced socket closing must not unregister its replacement
+                if turtle_registry
+                    .get(&turtle_id)
+                    .is_some_and(|(current, _)| *current == connection)
+                {
+                    turtle_registry.remove(&turtle_id);
+                    if let Some(info) = turtle_info.get_mut(&turtle_id) {
+                        info.online = false;
+                        announce(info);
+                    }
+                    save = true;
+                }
+            }
+            RegistryRequest::Seen(turtle_id, message) => {
+                if let Some(info) = turtle_info.get_mut(&turtle_id) {
+                    info.last_seen = unix_now();
+                    if let Some(message) = message {
+                        info.update(message);
+                    }
+                }
+            }
+            RegistryRequest::Get(turtle_id, sender) => {
+                let _ = sender.send(
+                    turtle_registry
+                        .get(&turtle_id)
+                        .map(|(_, sender)| sender.clone()),
+                );
+            }
+            RegistryRequest::List(sender) => {
+                let mut turtles: Vec<TurtleInfo> = turtle_info.values().cloned().collect();
+                turtles.sort_by(|a, b| a.id.cmp(&b.id));
+                let _ = sender.send(turtles);
+            }
+        }
+
+        if let (true, Some(path)) = (save, &store) {
+            save_turtles(path, &turtle_info).await;
         }
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct TurtleRegistry {
-    pub register_turtle: mpsc::UnboundedSender<(TurtleId, mpsc::UnboundedSender<Message>)>,
-    pub get_turtle: mpsc::UnboundedSender<(
-        TurtleId,
-        oneshot::Sender<Option<mpsc::UnboundedSender<Message>>>,
-    )>,
+    requests: mpsc::UnboundedSender<RegistryRequest>,
+    /// Everything turtles send that is not a reply to a command, as encoded
+    /// `Routed<TurtleMessage>` frames for every operator, along with the
+    /// turtle they came from.
+    pub telemetry: broadcast::Sender<(TurtleId, String)>,
+    /// Turtles coming online and going offline.
+    pub presence: broadcast::Sender<PresenceEvent>,
 }
 
 impl TurtleRegistry {
-    pub(crate) fn start() -> TurtleRegistry {
-        let (register_turtle, treg) = mpsc::unbounded_channel();
-        let (get_turtle, treq) = mpsc::unbounded_channel();
+    /// Starts the manager task, keeping known turtles in `store` if given.
+    pub(crate) fn start(store: Option<PathBuf>) -> TurtleRegistry {
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let (telemetry, _) = broadcast::channel(256);
+        let (presence, _) = broadcast::channel(64);
         let app_state = TurtleRegistry {
-            register_turtle,
-            get_turtle,
+            requests,
+            telemetry,
+            presence: presence.clone(),
         };
-        tokio::spawn(turtle_manager(treg, treq));
+        tokio::spawn(turtle_manager(receiver, presence, store));
         app_state
     }
+
+    /// Makes `sender` the way to reach `turtle_id`, replacing any socket the
+    /// turtle connected with before. Pass the returned id to
+    /// [`TurtleRegistry::unregister`] once the socket closes.
+    pub(crate) fn register(
+        &self,
+        turtle_id: TurtleId,
+        sender: mpsc::UnboundedSender<TurtleRequest>,
+    ) -> ConnectionId {
+        let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .requests
+            .send(RegistryRequest::Register(turtle_id, connection, sender));
+
+        connection
+    }
+
+    pub(crate) fn unregister(&self, turtle_id: TurtleId, connection: ConnectionId) {
+        let _ = self
+            .requests
+            .send(RegistryRequest::Unregister(turtle_id, connection));
+    }
+
+    /// Marks `turtle_id` as heard from just now, keeping `update` if it is
+    /// a status or pose.
+    pub(crate) fn seen(&self, turtle_id: TurtleId, update: Option<TurtleMessage>) {
+        let _ = self.requests.send(RegistryRequest::Seen(turtle_id, update));
+    }
+
+    /// The channel to the socket task of `turtle_id`, if it is connected.
+    pub(crate) async fn get(
+        &self,
+        turtle_id: TurtleId,
+    ) -> Option<mpsc::UnboundedSender<TurtleRequest>> {
+        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+        self.requests
+            .send(RegistryRequest::Get(turtle_id, oneshot_sender))
+            .ok()?;
+
+        oneshot_receiver.await.ok().flatten()
+    }
+
+    /// Every turtle that connected since the server started or that was
+    /// loaded from the store, sorted by id.
+    pub(crate) async fn list(&self) -> Vec<TurtleInfo> {
+        let (oneshot_sender, oneshot_receiver) = oneshot::channel();
+        if self
+            .requests
+            .send(RegistryRequest::List(oneshot_sender))
+            .is_err()
+        {
+            return vec![];
+        }
+
+        oneshot_receiver.await.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turtle(name: &str) -> TurtleId {
+        TurtleId(name.to_string())
+    }
+
+    #[tokio::test]
+    async fn turtles_go_offline_when_unregistered() {
+        let registry = TurtleRegistry::start(None);
+        let mut presence = registry.presence.subscribe();
+
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let connection = registry.register(turtle("miner"), sender);
+        let event = presence.recv().await.unwrap();
+        assert_eq!(event.turtle_id, turtle("miner"));
+        assert_eq!(event.presence, Presence::Online);
+        assert!(registry.get(turtle("miner")).await.is_some());
+
+        registry.unregister(turtle("miner"), connection);
+        assert_eq!(presence.recv().await.unwrap().presence, Presence::Offline);
+        assert!(registry.get(turtle("miner")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reconnecting_replaces_the_old_socket() {
+        let registry = TurtleRegistry::start(None);
+        let mut presence = registry.presence.subscribe();
+
+        let (old_sender, mut old_receiver) = mpsc::unbounded_channel();
+        let old_connection = registry.register(turtle("miner"), old_sender);
+        let (new_sender, _new_receiver) = mpsc::unbounded_channel();
+        registry.register(turtle("miner"), new_sender);
+
+        // The old socket task is told to stop, and closing it changes nothing
+        assert!(old_receiver.recv().await.is_none());
+        registry.unregister(turtle("miner"), old_connection);
+        assert!(registry.get(turtle("miner")).await.is_some());
+
+        assert_eq!(presence.recv().await.unwrap().presence, Presence::Online);
+        assert!(presence.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn known_turtles_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("turtles-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let registry = TurtleRegistry::start(Some(path.clone()));
+        let mut presence = registry.presence.subscribe();
+
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let connection = registry.register(turtle("miner"), sender);
+        // Sent straight after registering, like a socket task does
+        let pose = TurtleMessage::Pose {
+            x: 1,
+            y: 2,
+            z: 3,
+            heading: Heading::East,
+            source: PositionSource::Gps,
+        };
+        registry.seen(turtle("miner"), Some(pose));
+        registry.unregister(turtle("miner"), connection);
+        assert_eq!(presence.recv().await.unwrap().presence, Presence::Online);
+        assert_eq!(presence.recv().await.unwrap().presence, Presence::Offline);
+        // Handled after the save
+        registry.list().await;
+
+        let restarted = TurtleRegistry::start(Some(path.clone()));
+        let turtles = restarted.list().await;
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(turtles.len(), 1);
+        assert_eq!(turtles[0].id, "miner");
+        assert!(!turtles[0].online);
+        assert_eq!(turtles[0].position, Some([1, 2, 3]));
+    }
 }